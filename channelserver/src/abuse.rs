@@ -0,0 +1,378 @@
+//! An in-process abuse ledger, keyed by remote IP.
+//!
+//! Misbehavior (connecting to a full channel, probing for unknown channels,
+//! going over limits, protocol errors) adds weighted points to the remote's
+//! score. Scores decay exponentially with a half-life of `abuse_half_life`
+//! seconds, so occasional accidents fade away, but repeat offenders add up.
+//! Once a score reaches `abuse_threshold`, the remote is banned for
+//! `abuse_ban_duration` seconds.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web_actors::ws;
+use cadence::{Counted, StatsdClient};
+use slog::{debug, warn};
+
+use crate::logging::MozLogger;
+use crate::privacy::DisclosurePolicy;
+use crate::ratelimit::RateLimitAction;
+use crate::settings::Settings;
+
+/// Scores below this are forgotten when pruning.
+const FORGET_SCORE: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Infraction {
+    /// Tried to join a channel that already had all its participants.
+    ChannelFull,
+    /// Tried to join a channel that doesn't exist.
+    UnknownChannel,
+    /// Tried to rejoin a channel with a token that wasn't issued for it.
+    InvalidToken,
+    /// Presented a bad proof-of-work solution.
+    InvalidPow,
+    /// Went over a channel's message or data limits.
+    LimitExceeded,
+    /// Sent messages faster than allowed.
+    RateLimited,
+    /// Sent something that isn't a valid websocket text exchange.
+    ProtocolError,
+}
+
+impl Infraction {
+    /// How many points the infraction is worth.
+    pub fn weight(self) -> f64 {
+        match self {
+            Infraction::ChannelFull => 1.0,
+            Infraction::UnknownChannel => 5.0,
+            Infraction::InvalidToken => 5.0,
+            Infraction::InvalidPow => 5.0,
+            Infraction::LimitExceeded => 10.0,
+            Infraction::RateLimited => 5.0,
+            Infraction::ProtocolError => 5.0,
+        }
+    }
+
+    fn metric(self) -> &'static str {
+        match self {
+            Infraction::ChannelFull => "abuse.channel_full",
+            Infraction::UnknownChannel => "abuse.unknown_channel",
+            Infraction::InvalidToken => "abuse.invalid_token",
+            Infraction::InvalidPow => "abuse.invalid_pow",
+            Infraction::LimitExceeded => "abuse.limit_exceeded",
+            Infraction::RateLimited => "abuse.rate_limited",
+            Infraction::ProtocolError => "abuse.protocol_error",
+        }
+    }
+
+    /// The infraction for a websocket error, if it's the client's doing.
+    /// Network failures (resets, timeouts) aren't held against it.
+    pub fn from_ws_error(err: &ws::ProtocolError) -> Option<Self> {
+        match err {
+            ws::ProtocolError::Io(_) => None,
+            _ => Some(Infraction::ProtocolError),
+        }
+    }
+
+    /// The infraction for going over the rate limits, if the message is lost
+    /// for it. Messages the operator chose to delay are still relayed, so
+    /// they aren't held against the client.
+    pub fn from_rate_limit(action: RateLimitAction) -> Option<Self> {
+        match action {
+            RateLimitAction::Delay => None,
+            RateLimitAction::Drop | RateLimitAction::Disconnect => Some(Infraction::RateLimited),
+        }
+    }
+}
+
+impl fmt::Display for Infraction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Infraction::ChannelFull => "channel_full",
+                Infraction::UnknownChannel => "unknown_channel",
+                Infraction::InvalidToken => "invalid_token",
+                Infraction::InvalidPow => "invalid_pow",
+                Infraction::LimitExceeded => "limit_exceeded",
+                Infraction::RateLimited => "rate_limited",
+                Infraction::ProtocolError => "protocol_error",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Score {
+    points: f64,
+    updated: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Score {
+    /// Bring the score's decay up to `now`.
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        if half_life > Duration::from_secs(0) {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.points *= 0.5f64.powf(elapsed / half_life.as_secs_f64());
+        }
+        self.updated = now;
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map(|until| until > now).unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct AbuseConfig {
+    threshold: f64,
+    half_life: Duration,
+    ban_duration: Duration,
+}
+
+/// Shared abuse scores. Clones refer to the same ledger.
+#[derive(Clone)]
+pub struct AbuseLedger {
+    scores: Arc<Mutex<HashMap<IpAddr, Score>>>,
+    config: AbuseConfig,
+    /// what of an address may be logged, kept up to date on reloads
+    privacy: Arc<RwLock<DisclosurePolicy>>,
+    log: MozLogger,
+    metrics: StatsdClient,
+}
+
+impl fmt::Debug for AbuseLedger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AbuseLedger{{ config: {:?} }}", self.config)
+    }
+}
+
+impl AbuseLedger {
+    pub fn new(
+        settings: &Settings,
+        log: &MozLogger,
+        metrics: &StatsdClient,
+        privacy: &DisclosurePolicy,
+    ) -> Self {
+        Self {
+            scores: Arc::new(Mutex::new(HashMap::new())),
+            config: AbuseConfig {
+                threshold: settings.abuse_threshold as f64,
+                half_life: Duration::from_secs(settings.abuse_half_life),
+                ban_duration: Duration::from_secs(settings.abuse_ban_duration),
+            },
+            privacy: Arc::new(RwLock::new(privacy.clone())),
+            log: log.clone(),
+            metrics: metrics.clone(),
+        }
+    }
+
+    /// Log addresses as `privacy` allows, from now on.
+    pub fn set_privacy(&self, privacy: &DisclosurePolicy) {
+        *self.privacy.write().unwrap_or_else(|e| e.into_inner()) = privacy.clone();
+    }
+
+    /// Is abuse tracking turned on?
+    pub fn is_enabled(&self) -> bool {
+        self.config.threshold > 0.0
+    }
+
+    /// Add an infraction to the remote's score. Returns true if this pushed
+    /// the remote into a ban.
+    pub fn record(&self, remote: &Option<String>, infraction: Infraction) -> bool {
+        self.record_at(remote, infraction, Instant::now())
+    }
+
+    fn record_at(&self, remote: &Option<String>, infraction: Infraction, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let addr = match remote.as_ref().and_then(|r| r.parse::<IpAddr>().ok()) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        self.metrics.incr(infraction.metric()).ok();
+        let mut scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        let score = scores.entry(addr).or_insert(Score {
+            points: 0.0,
+            updated: now,
+            banned_until: None,
+        });
+        score.decay(now, self.config.half_life);
+        score.points += infraction.weight();
+        let remote_ip = self
+            .privacy
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .disclose_remote(remote);
+        debug!(
+            self.log.log,
+            "Abuse infraction";
+            "remote_ip" => &remote_ip,
+            "infraction" => infraction.to_string(),
+            "score" => score.points,
+        );
+        if score.points < self.config.threshold || score.is_banned(now) {
+            return false;
+        }
+        score.banned_until = Some(now + self.config.ban_duration);
+        warn!(
+            self.log.log,
+            "Temporarily banning remote";
+            "remote_ip" => &remote_ip,
+            "infraction" => infraction.to_string(),
+            "score" => score.points,
+            "ban_secs" => self.config.ban_duration.as_secs(),
+        );
+        self.metrics.incr("abuse.ban").ok();
+        true
+    }
+
+    /// Is the remote currently banned?
+    pub fn is_banned(&self, addr: &IpAddr) -> bool {
+        self.is_banned_at(addr, Instant::now())
+    }
+
+    fn is_banned_at(&self, addr: &IpAddr, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        scores
+            .get(addr)
+            .map(|score| score.is_banned(now))
+            .unwrap_or(false)
+    }
+
+    /// Forget remotes whose bans are over and whose scores have decayed away.
+    pub fn prune(&self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn prune_at(&self, now: Instant) {
+        let half_life = self.config.half_life;
+        let mut scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        scores.retain(|_, score| {
+            score.decay(now, half_life);
+            score.is_banned(now) || score.points >= FORGET_SCORE
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io;
+
+    use cadence::NopMetricSink;
+
+    fn ledger(threshold: u64) -> AbuseLedger {
+        let settings = Settings {
+            abuse_threshold: threshold,
+            abuse_half_life: 60,
+            abuse_ban_duration: 300,
+            ..Default::default()
+        };
+        AbuseLedger::new(
+            &settings,
+            &MozLogger::new_human(),
+            &StatsdClient::from_sink("test", NopMetricSink),
+            &DisclosurePolicy::new(&settings),
+        )
+    }
+
+    #[test]
+    fn test_disabled() {
+        let ledger = ledger(0);
+        let remote = Some("1.2.3.4".to_owned());
+        for _ in 0..100 {
+            assert!(!ledger.record(&remote, Infraction::LimitExceeded));
+        }
+        assert!(!ledger.is_banned(&"1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ban() {
+        let ledger = ledger(20);
+        let now = Instant::now();
+        let remote = Some("1.2.3.4".to_owned());
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(!ledger.record_at(&remote, Infraction::LimitExceeded, now));
+        assert!(!ledger.is_banned_at(&addr, now));
+        assert!(ledger.record_at(&remote, Infraction::LimitExceeded, now));
+        assert!(ledger.is_banned_at(&addr, now));
+        // Further infractions while banned don't re-trigger the ban.
+        assert!(!ledger.record_at(&remote, Infraction::ChannelFull, now));
+        // Other remotes are unaffected.
+        assert!(!ledger.is_banned_at(&"1.2.3.5".parse().unwrap(), now));
+        // Bans expire.
+        let later = now + Duration::from_secs(301);
+        assert!(!ledger.is_banned_at(&addr, later));
+        // Unparsable remotes are ignored.
+        assert!(!ledger.record_at(&Some("unknown".to_owned()), Infraction::LimitExceeded, now));
+        assert!(!ledger.record_at(&None, Infraction::LimitExceeded, now));
+    }
+
+    #[test]
+    fn test_decay() {
+        let ledger = ledger(20);
+        let remote = Some("1.2.3.4".to_owned());
+        let mut now = Instant::now();
+        // Spread out, the same infractions never add up to a ban.
+        for _ in 0..10 {
+            assert!(!ledger.record_at(&remote, Infraction::LimitExceeded, now));
+            now += Duration::from_secs(60);
+        }
+        ledger.prune_at(now);
+        assert_eq!(ledger.scores.lock().unwrap().len(), 1);
+        ledger.prune_at(now + Duration::from_secs(600));
+        assert!(ledger.scores.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ws_errors() {
+        assert_eq!(
+            Infraction::from_ws_error(&ws::ProtocolError::UnmaskedFrame),
+            Some(Infraction::ProtocolError)
+        );
+        assert_eq!(
+            Infraction::from_ws_error(&ws::ProtocolError::Overflow),
+            Some(Infraction::ProtocolError)
+        );
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            Infraction::from_ws_error(&ws::ProtocolError::Io(reset)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_delayed_burst() {
+        let ledger = ledger(10);
+        let remote = Some("1.2.3.4".to_owned());
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+        // A client slightly over the rate has every message delayed, and
+        // isn't banned for it, however long it keeps it up.
+        for _ in 0..100 {
+            let action = RateLimitAction::Delay.resolve(Duration::from_millis(100));
+            if let Some(infraction) = Infraction::from_rate_limit(action) {
+                ledger.record_at(&remote, infraction, now);
+            }
+        }
+        assert!(!ledger.is_banned_at(&addr, now));
+        // One that would be held too long has its messages dropped, which counts.
+        let action = RateLimitAction::Delay.resolve(Duration::from_secs(5));
+        assert_eq!(action, RateLimitAction::Drop);
+        for _ in 0..2 {
+            ledger.record_at(&remote, Infraction::from_rate_limit(action).unwrap(), now);
+        }
+        assert!(ledger.is_banned_at(&addr, now));
+    }
+}
@@ -0,0 +1,158 @@
+//! Autonomous system lookups.
+//!
+//! When `asn_mmdb_loc` is set, each client's address is also looked up in a
+//! MaxMind GeoLite2 ASN database, for the number and organization of the
+//! network it connects from (e.g. 7922, "Comcast Cable Communications, LLC").
+//! Peers are told these, so that they can say who the other side is
+//! connected through, and they're logged when a channel is created or
+//! joined, for abuse analytics. Either way, the disclosure policy (see
+//! `privacy`) applies.
+//!
+//! Like the City database, it's memory mapped, and checked for changes every
+//! `geo_reload` seconds.
+use std::net::IpAddr;
+use std::time::Duration;
+
+use cadence::{Counted, StatsdClient};
+use slog::{debug, info};
+
+use crate::error::HandlerError;
+use crate::geo::{self, DatabaseKind, MaxMindProvider};
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// The network an address belongs to.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Network {
+    /// Autonomous system number
+    pub number: Option<u32>,
+    /// Organization the autonomous system is registered to
+    pub organization: Option<String>,
+}
+
+/// Looks addresses up in a MaxMind GeoLite2 ASN database. Clones share the
+/// same, swappable, database.
+#[derive(Clone)]
+pub struct AsnProvider {
+    db: MaxMindProvider,
+    metrics: StatsdClient,
+}
+
+impl AsnProvider {
+    pub fn open(path: &str, metrics: &StatsdClient) -> Result<Self, HandlerError> {
+        Ok(Self {
+            db: MaxMindProvider::open_kind(path, DatabaseKind::Asn, metrics)?,
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// Find the network `addr` belongs to, if the database knows it.
+    pub fn lookup(&self, addr: IpAddr, log: &MozLogger) -> Option<Network> {
+        let record = match self.db.reader().lookup::<maxminddb::geoip2::Asn>(addr) {
+            Ok(record) => record,
+            Err(err) => {
+                if geo::handle_city_err(log, &err) {
+                    self.metrics.incr("asn.lookup_error").ok();
+                }
+                debug!(log.log, "No ASN info for IP");
+                return None;
+            }
+        };
+        let network = Network {
+            number: record.autonomous_system_number,
+            organization: record.autonomous_system_organization,
+        };
+        if network == Network::default() {
+            return None;
+        }
+        Some(network)
+    }
+}
+
+/// Open the ASN database, if one is configured, and watch it for changes.
+pub fn from_settings(
+    settings: &Settings,
+    log: &MozLogger,
+    metrics: &StatsdClient,
+) -> Result<Option<AsnProvider>, HandlerError> {
+    if settings.asn_mmdb_loc.is_empty() {
+        return Ok(None);
+    }
+    let provider = AsnProvider::open(&settings.asn_mmdb_loc, metrics)?;
+    provider
+        .db
+        .watch(Duration::from_secs(settings.geo_reload), log);
+    info!(log.log, "ASN database: {}", settings.asn_mmdb_loc);
+    Ok(Some(provider))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::geo::test::{city_mmdb, mmdb, nop_metrics};
+
+    /// An ASN database covering 63.245.208.0/24 and 10.0.0.0/8.
+    pub fn asn_mmdb(database_type: &str) -> Vec<u8> {
+        mmdb(
+            database_type,
+            &[
+                (
+                    "63.245.208.0/24",
+                    json!({
+                        "autonomous_system_number": 53_319,
+                        "autonomous_system_organization": "MOZILLA-MDC1",
+                    }),
+                ),
+                ("10.0.0.0/8", json!({"autonomous_system_number": 64_512})),
+            ],
+        )
+    }
+
+    fn open(contents: &[u8]) -> Result<AsnProvider, HandlerError> {
+        let path = std::env::temp_dir().join(format!(
+            "asn-{}-{}.mmdb",
+            std::process::id(),
+            contents.len()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let provider = AsnProvider::open(&path.to_string_lossy(), &nop_metrics());
+        std::fs::remove_file(&path).ok();
+        provider
+    }
+
+    #[test]
+    fn test_lookup() {
+        let log = MozLogger::new_human();
+        let provider = open(&asn_mmdb("GeoLite2-ASN")).unwrap();
+        let lookup = |ip: &str| provider.lookup(ip.parse().unwrap(), &log);
+        assert_eq!(
+            lookup("63.245.208.195"),
+            Some(Network {
+                number: Some(53_319),
+                organization: Some("MOZILLA-MDC1".to_owned()),
+            })
+        );
+        assert_eq!(
+            lookup("10.1.2.3"),
+            Some(Network {
+                number: Some(64_512),
+                organization: None,
+            })
+        );
+        assert_eq!(lookup("192.168.1.1"), None);
+    }
+
+    #[test]
+    fn test_open() {
+        assert!(open(&city_mmdb("GeoLite2-City")).is_err());
+        assert!(open(b"garbage").is_err());
+        let settings = Settings::default();
+        let log = MozLogger::new_human();
+        assert!(from_settings(&settings, &log, &nop_metrics())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -20,6 +20,12 @@ pub enum HandlerErrorKind {
     MetricsError(String),
     #[fail(display = "Bad remote address: {:?}", _0)]
     BadRemoteAddrError(String),
+    #[fail(display = "Invalid proof of work: {:?}", _0)]
+    PowError(String),
+    #[fail(display = "TLS Error: {:?}", _0)]
+    TlsError(String),
+    #[fail(display = "Geolocation Error: {:?}", _0)]
+    GeoError(String),
 }
 
 impl Fail for HandlerError {
@@ -0,0 +1,1128 @@
+//! Geolocation of connecting clients.
+//!
+//! `geo_provider` is a comma delimited list of providers, asked in order.
+//! Each fills in the parts of the location (city, region, country) that the
+//! ones before it left out, as long as it agrees on every part already found
+//! (by code, or by name where there are no codes):
+//!
+//! * `maxmind` looks the address up in the MaxMind database at `mmdb_loc`.
+//! * `header` reads the locations CDNs and load balancers pass along in
+//!   request headers, as described by `geo_headers` (see `geoheaders`).
+//!   `fallback` mappings are asked where `header` appears in the list;
+//!   `prefer` mappings are asked before every other provider.
+//! * `static` looks the address up in the table in `geo_static_file`.
+//! * `none` doesn't locate anyone.
+//!
+//! The static table holds one `<range> <country>; <region>; <city>` entry per
+//! line (`#` starts a comment). Trailing fields may be left out. The most
+//! specific range containing the address wins.
+//!
+//! The MaxMind database is checked for changes every `geo_reload` seconds,
+//! e.g. when `mmdb/latest` is pointed at a new release. A new database is
+//! swapped in once it has been loaded and checked; if it can't be, the
+//! current one stays in use. Lookup failures leave the client unlocated.
+//!
+//! Providers hand back every localized name they know for a place, so that
+//! each peer can be shown it in its own language.
+//!
+//! The database is memory mapped rather than read into memory, so its pages
+//! are shared with the page cache and only one copy is held no matter how
+//! many workers use it. Replace the file (e.g. by renaming a new one over it,
+//! or repointing a symlink) rather than rewriting it in place: the mapping
+//! reads the file's pages directly, so a file that is truncated or rewritten
+//! under a live reader can crash the server with `SIGBUS`, or hand back
+//! garbage.
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::{http::HeaderMap, web};
+use cadence::{Counted, Gauged, StatsdClient, Timed};
+use ipnet::IpNet;
+use maxminddb::{self, MaxMindDBError};
+use memmap::Mmap;
+use serde::Deserialize;
+use slog::{debug, error, info, warn};
+
+use crate::error::{HandlerError, HandlerErrorKind};
+use crate::geoheaders::{self, Mode};
+use crate::language::Languages;
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// A place name, either as the provider has it, or in every language the
+/// provider knows it in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Name {
+    Plain(String),
+    /// Names keyed by language tag, e.g. "en" or "pt-BR"
+    Localized(BTreeMap<String, String>),
+}
+
+impl Name {
+    /// The name in the best of `langs`, if the place has one.
+    pub fn localize(&self, langs: &Languages) -> Option<String> {
+        match self {
+            Name::Plain(name) => Some(name.clone()),
+            Name::Localized(names) => langs.choose(names),
+        }
+    }
+
+    /// Whether the two could name the same place, i.e. share a name in some
+    /// language.
+    pub fn matches(&self, other: &Name) -> bool {
+        let names = |name: &Name| -> Vec<String> {
+            match name {
+                Name::Plain(name) => vec![name.to_lowercase()],
+                Name::Localized(names) => names.values().map(|n| n.to_lowercase()).collect(),
+            }
+        };
+        let theirs = names(other);
+        names(self).iter().any(|name| theirs.contains(name))
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Self {
+        Name::Plain(name.to_owned())
+    }
+}
+
+/// Where a client is.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Location {
+    pub city: Option<Name>,
+    pub region: Option<Name>,
+    pub country: Option<Name>,
+    /// ISO 3166-1 alpha-2 code, e.g. "US"
+    pub country_code: Option<String>,
+    /// ISO 3166-2 code, e.g. "US-CA"
+    pub region_code: Option<String>,
+    /// Two letter continent code, e.g. "NA"
+    pub continent: Option<String>,
+    /// IANA time zone, e.g. "America/Los_Angeles"
+    pub time_zone: Option<String>,
+    /// Kilometers around the location the client is likely within
+    pub accuracy_radius: Option<u16>,
+}
+
+impl Location {
+    pub fn is_empty(&self) -> bool {
+        *self == Location::default()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.has_country() && self.has_region() && self.has_city()
+    }
+
+    fn has_country(&self) -> bool {
+        self.country.is_some() || self.country_code.is_some() || self.continent.is_some()
+    }
+
+    fn has_region(&self) -> bool {
+        self.region.is_some() || self.region_code.is_some()
+    }
+
+    fn has_city(&self) -> bool {
+        self.city.is_some() || self.time_zone.is_some() || self.accuracy_radius.is_some()
+    }
+
+    /// Fill in whatever is missing from `other`. Each part (the country with
+    /// its codes, the region with its code, the city with its time zone and
+    /// accuracy radius) is taken whole from one location, so that e.g. one
+    /// provider's time zone isn't paired with another's city. Nothing is
+    /// taken from a location that doesn't agree on every part already found,
+    /// including one that leaves that part out.
+    pub fn fill(&mut self, other: Location) {
+        let agrees = (!self.has_country()
+            || same_place(
+                (&self.country_code, &other.country_code),
+                (&self.country, &other.country),
+            ))
+            && (!self.has_region()
+                || same_place(
+                    (&self.region_code, &other.region_code),
+                    (&self.region, &other.region),
+                ))
+            && (!self.has_city()
+                || same_place(
+                    (&self.time_zone, &other.time_zone),
+                    (&self.city, &other.city),
+                ));
+        if !agrees {
+            return;
+        }
+        if !self.has_country() {
+            self.country = other.country;
+            self.country_code = other.country_code;
+            self.continent = other.continent;
+        }
+        if !self.has_region() {
+            self.region = other.region;
+            self.region_code = other.region_code;
+        }
+        if !self.has_city() {
+            self.city = other.city;
+            self.time_zone = other.time_zone;
+            self.accuracy_radius = other.accuracy_radius;
+        }
+    }
+}
+
+/// Whether two locations are in the same place at one level, going by
+/// whichever of their codes and names both have. Places with nothing to
+/// compare aren't the same.
+fn same_place(
+    codes: (&Option<String>, &Option<String>),
+    names: (&Option<Name>, &Option<Name>),
+) -> bool {
+    let codes = match codes {
+        (Some(ours), Some(theirs)) => Some(ours == theirs),
+        _ => None,
+    };
+    let names = match names {
+        (Some(ours), Some(theirs)) => Some(ours.matches(theirs)),
+        _ => None,
+    };
+    match (codes, names) {
+        (None, None) => false,
+        (codes, names) => codes != Some(false) && names != Some(false),
+    }
+}
+
+/// What's known about a client when locating it.
+pub struct Client<'a> {
+    /// The client's address, if it could be determined
+    pub addr: Option<IpAddr>,
+    /// Whether the connection came through a trusted proxy, which makes the
+    /// headers the proxy sets believable
+    pub via_trusted_proxy: bool,
+    pub headers: &'a HeaderMap,
+}
+
+pub trait GeoProvider: Send + Sync {
+    /// Locate a client. Parts of the location that can't be found are left
+    /// out; None if nothing could be.
+    fn locate(&self, client: &Client, log: &MozLogger) -> Option<Location>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProviderKind {
+    MaxMind,
+    Header,
+    Static,
+    None,
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "maxmind" => Ok(ProviderKind::MaxMind),
+            "header" => Ok(ProviderKind::Header),
+            "static" => Ok(ProviderKind::Static),
+            "none" => Ok(ProviderKind::None),
+            _ => Err(format!("Unknown geolocation provider: {:?}", s.trim())),
+        }
+    }
+}
+
+/// Parse the comma delimited `geo_provider` list.
+pub fn parse_providers(list: &str) -> Result<Vec<ProviderKind>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Build the providers named by `geo_provider`. The result is meant to be
+/// built once and shared by every worker. The MaxMind database is watched for
+/// changes from the current arbiter.
+pub fn from_settings(
+    settings: &Settings,
+    log: &MozLogger,
+    metrics: &StatsdClient,
+) -> Result<Box<dyn GeoProvider>, HandlerError> {
+    let kinds = parse_providers(&settings.geo_provider)
+        .map_err(|err| HandlerError::from(HandlerErrorKind::GeoError(err)))?;
+    let mappings = if kinds.contains(&ProviderKind::Header) {
+        geoheaders::parse_mappings(&settings.geo_headers)
+            .map_err(|err| HandlerError::from(HandlerErrorKind::GeoError(err)))?
+    } else {
+        Vec::new()
+    };
+    let mut providers: Vec<Box<dyn GeoProvider>> = Vec::new();
+    for mapping in mappings.iter().filter(|m| m.mode == Mode::Prefer) {
+        providers.push(Box::new(mapping.clone()));
+    }
+    for kind in kinds {
+        match kind {
+            ProviderKind::Header => {
+                for mapping in mappings.iter().filter(|m| m.mode == Mode::Fallback) {
+                    providers.push(Box::new(mapping.clone()));
+                }
+            }
+            ProviderKind::MaxMind => {
+                let provider = MaxMindProvider::open(&settings.mmdb_loc, metrics)?;
+                provider.watch(Duration::from_secs(settings.geo_reload), log);
+                providers.push(Box::new(provider));
+            }
+            ProviderKind::Static => {
+                providers.push(Box::new(StaticProvider::open(&settings.geo_static_file)?))
+            }
+            ProviderKind::None => {}
+        }
+    }
+    info!(log.log, "Geolocation providers: {}", settings.geo_provider);
+    if providers.is_empty() {
+        return Ok(Box::new(NoProvider));
+    }
+    Ok(Box::new(ProviderChain(providers)))
+}
+
+/// Asks each provider in turn to fill in what's still missing.
+pub struct ProviderChain(Vec<Box<dyn GeoProvider>>);
+
+impl GeoProvider for ProviderChain {
+    fn locate(&self, client: &Client, log: &MozLogger) -> Option<Location> {
+        let mut location = Location::default();
+        for provider in &self.0 {
+            if let Some(found) = provider.locate(client, log) {
+                location.fill(found);
+                if location.is_complete() {
+                    break;
+                }
+            }
+        }
+        if location.is_empty() {
+            return None;
+        }
+        Some(location)
+    }
+}
+
+/// Doesn't locate anyone.
+pub struct NoProvider;
+
+impl GeoProvider for NoProvider {
+    fn locate(&self, _client: &Client, _log: &MozLogger) -> Option<Location> {
+        None
+    }
+}
+
+pub type Reader = maxminddb::Reader<Mmap>;
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(path))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// The parts of a GeoIP2 or GeoLite2 City record that are used.
+/// `maxminddb::geoip2::City` has no `accuracy_radius`, so records are read
+/// into these instead.
+#[derive(Debug, Default, Deserialize)]
+struct CityRecord {
+    city: Option<PlaceRecord>,
+    continent: Option<PlaceRecord>,
+    country: Option<PlaceRecord>,
+    location: Option<LocationRecord>,
+    subdivisions: Option<Vec<PlaceRecord>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PlaceRecord {
+    /// Continent code
+    code: Option<String>,
+    /// Country or subdivision code
+    iso_code: Option<String>,
+    names: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LocationRecord {
+    accuracy_radius: Option<u16>,
+    time_zone: Option<String>,
+}
+
+/// The kinds of MaxMind database that are read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DatabaseKind {
+    /// GeoIP2 or GeoLite2 City, for `mmdb_loc`
+    City,
+    /// GeoLite2 ASN, for `asn_mmdb_loc`
+    Asn,
+}
+
+impl DatabaseKind {
+    /// What the database's `database_type` contains.
+    fn type_name(self) -> &'static str {
+        match self {
+            DatabaseKind::City => "City",
+            DatabaseKind::Asn => "ASN",
+        }
+    }
+
+    /// The name of one of the database's metrics, e.g. `geo.reload`.
+    fn metric(self, name: &str) -> String {
+        let prefix = match self {
+            DatabaseKind::City => "geo",
+            DatabaseKind::Asn => "asn",
+        };
+        format!("{}.{}", prefix, name)
+    }
+}
+
+/// Load a database, and make sure it can be searched. Records how long that
+/// took and the size of the file.
+fn load(path: &str, kind: DatabaseKind, metrics: &StatsdClient) -> Result<Reader, String> {
+    let started = Instant::now();
+    let reader = check(path, kind, maxminddb::Reader::open_mmap(path))?;
+    metrics
+        .time_duration(&kind.metric("load_time"), started.elapsed())
+        .ok();
+    if let Ok(meta) = fs::metadata(path) {
+        metrics.gauge(&kind.metric("db_size"), meta.len()).ok();
+    }
+    Ok(reader)
+}
+
+fn check(
+    path: &str,
+    kind: DatabaseKind,
+    opened: Result<Reader, MaxMindDBError>,
+) -> Result<Reader, String> {
+    let reader = opened.map_err(|err| format!("Could not open {:?}: {:?}", path, err))?;
+    if !reader.metadata.database_type.contains(kind.type_name()) {
+        return Err(format!(
+            "{:?} is a {} database, not a {} database",
+            path,
+            reader.metadata.database_type,
+            kind.type_name()
+        ));
+    }
+    let probe = IpAddr::from([1, 1, 1, 1]);
+    let searched = match kind {
+        DatabaseKind::City => reader.lookup::<CityRecord>(probe).map(|_| ()),
+        DatabaseKind::Asn => reader.lookup::<maxminddb::geoip2::Asn>(probe).map(|_| ()),
+    };
+    match searched {
+        Ok(_) | Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(reader),
+        Err(err) => Err(format!("Could not search {:?}: {:?}", path, err)),
+    }
+}
+
+/// Looks addresses up in a MaxMind GeoIP2 or GeoLite2 City database, or in
+/// another kind of MaxMind database with `reader`. Clones share the same,
+/// swappable, database.
+#[derive(Clone)]
+pub struct MaxMindProvider {
+    path: String,
+    kind: DatabaseKind,
+    reader: Arc<RwLock<Arc<Reader>>>,
+    modified: Arc<Mutex<Option<SystemTime>>>,
+    metrics: StatsdClient,
+}
+
+impl MaxMindProvider {
+    pub fn open(path: &str, metrics: &StatsdClient) -> Result<Self, HandlerError> {
+        Self::open_kind(path, DatabaseKind::City, metrics)
+    }
+
+    pub fn open_kind(
+        path: &str,
+        kind: DatabaseKind,
+        metrics: &StatsdClient,
+    ) -> Result<Self, HandlerError> {
+        let modified = modified_time(path);
+        let reader = load(path, kind, metrics).map_err(HandlerErrorKind::GeoError)?;
+        Ok(Self {
+            path: path.to_owned(),
+            kind,
+            reader: Arc::new(RwLock::new(Arc::new(reader))),
+            modified: Arc::new(Mutex::new(modified)),
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// Whether the database file has been modified since it was last read.
+    fn changed(&self) -> bool {
+        let modified = modified_time(&self.path);
+        let mut last = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+        if *last == modified {
+            return false;
+        }
+        *last = modified;
+        true
+    }
+
+    /// Swap in a newly loaded database, or keep the current one if it
+    /// couldn't be loaded. Returns true if the database was replaced.
+    fn swap(&self, loaded: Result<Reader, String>, log: &MozLogger) -> bool {
+        match loaded {
+            Ok(reader) => {
+                info!(
+                    log.log,
+                    "Loaded GeoIP database";
+                    "path" => &self.path,
+                    "type" => &reader.metadata.database_type,
+                    "build_epoch" => reader.metadata.build_epoch,
+                );
+                *self.reader.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(reader);
+                self.metrics.incr(&self.kind.metric("reload")).ok();
+                true
+            }
+            Err(err) => {
+                error!(
+                    log.log,
+                    "Could not reload GeoIP database, keeping the current one: {}", err;
+                    "path" => &self.path,
+                );
+                self.metrics.incr(&self.kind.metric("reload_error")).ok();
+                false
+            }
+        }
+    }
+
+    /// Re-read the database if it has been modified since it was last read.
+    /// Returns true if the database was replaced.
+    pub fn reload_if_changed(&self, log: &MozLogger) -> bool {
+        if !self.changed() {
+            return false;
+        }
+        self.swap(load(&self.path, self.kind, &self.metrics), log)
+    }
+
+    /// The database currently in use.
+    pub fn reader(&self) -> Arc<Reader> {
+        self.reader
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Check the database for changes every `interval`. The database is
+    /// loaded off the event loop.
+    pub fn watch(&self, interval: Duration, log: &MozLogger) {
+        if interval == Duration::from_secs(0) {
+            return;
+        }
+        let provider = self.clone();
+        let log = log.clone();
+        actix_rt::spawn(async move {
+            let mut ticker = actix_rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let (provider, log) = (provider.clone(), log.clone());
+                web::block(move || Ok::<_, ()>(provider.reload_if_changed(&log)))
+                    .await
+                    .ok();
+            }
+        });
+    }
+}
+
+/// Log a failed lookup. Returns true if it was a failure of the database,
+/// rather than the address not being in it.
+#[allow(unreachable_patterns)]
+pub fn handle_city_err(log: &MozLogger, err: &MaxMindDBError) -> bool {
+    match err {
+        maxminddb::MaxMindDBError::InvalidDatabaseError(s) => {
+            error!(log.log, "Invalid GeoIP database! {:?}", s)
+        }
+        maxminddb::MaxMindDBError::IoError(s) => error!(log.log, "Could not read database {:?}", s),
+        maxminddb::MaxMindDBError::MapError(s) => warn!(log.log, "Mapping error: {:?}", s),
+        maxminddb::MaxMindDBError::DecodingError(s) => {
+            warn!(log.log, "Could not decode mapping result: {:?}", s)
+        }
+        maxminddb::MaxMindDBError::AddressNotFoundError(s) => {
+            debug!(log.log, "Could not find address for IP: {:?}", s);
+            return false;
+        }
+        // include to future proof against cross compile dependency errors
+        _ => error!(log.log, "Unknown GeoIP error encountered: {:?}", err),
+    };
+    true
+}
+
+impl GeoProvider for MaxMindProvider {
+    fn locate(&self, client: &Client, log: &MozLogger) -> Option<Location> {
+        let addr = client.addr?;
+        let record = match self.reader().lookup::<CityRecord>(addr) {
+            Ok(record) => record,
+            Err(err) => {
+                if handle_city_err(log, &err) {
+                    self.metrics.incr(&self.kind.metric("lookup_error")).ok();
+                }
+                info!(log.log, "No location info for IP");
+                return None;
+            }
+        };
+        let country = record.country.unwrap_or_default();
+        // Only the largest subdivision is used, e.g. the state, not the county.
+        let region = record
+            .subdivisions
+            .and_then(|subdivisions| subdivisions.into_iter().next())
+            .unwrap_or_default();
+        let location_record = record.location.unwrap_or_default();
+        let location = Location {
+            city: record.city.and_then(|city| city.names).map(Name::Localized),
+            region: region.names.map(Name::Localized),
+            country: country.names.map(Name::Localized),
+            region_code: match (&country.iso_code, region.iso_code) {
+                (Some(country), Some(region)) => Some(format!("{}-{}", country, region)),
+                _ => None,
+            },
+            country_code: country.iso_code,
+            continent: record.continent.and_then(|continent| continent.code),
+            time_zone: location_record.time_zone,
+            accuracy_radius: location_record.accuracy_radius,
+        };
+        if location.is_empty() {
+            return None;
+        }
+        Some(location)
+    }
+}
+
+/// Looks addresses up in a fixed table, e.g. of the networks in a test or
+/// development environment.
+#[derive(Debug, Default)]
+pub struct StaticProvider {
+    table: Vec<(IpNet, Location)>,
+}
+
+impl StaticProvider {
+    pub fn open(path: &str) -> Result<Self, HandlerError> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            HandlerErrorKind::GeoError(format!("Could not read {:?}: {:?}", path, err))
+        })?;
+        text.parse()
+            .map_err(|err| HandlerErrorKind::GeoError(format!("{:?}: {}", path, err)).into())
+    }
+}
+
+impl FromStr for StaticProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut table = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (range, names) = match line.find(char::is_whitespace) {
+                Some(pos) => (&line[..pos], &line[pos..]),
+                None => (line, ""),
+            };
+            let net = match range.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => match range.parse::<IpAddr>() {
+                    Ok(addr) => IpNet::from(addr),
+                    Err(_) => {
+                        return Err(format!("line {}: {:?} is not a range", number + 1, range))
+                    }
+                },
+            };
+            let mut names = names.split(';').map(str::trim).map(|s| {
+                if s.is_empty() {
+                    None
+                } else {
+                    Some(Name::from(s))
+                }
+            });
+            let location = Location {
+                country: names.next().flatten(),
+                region: names.next().flatten(),
+                city: names.next().flatten(),
+                ..Default::default()
+            };
+            table.push((net, location));
+        }
+        Ok(Self { table })
+    }
+}
+
+impl GeoProvider for StaticProvider {
+    fn locate(&self, client: &Client, _log: &MozLogger) -> Option<Location> {
+        let addr = client.addr?;
+        self.table
+            .iter()
+            .filter(|(net, _)| net.contains(&addr))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, location)| location.clone())
+            .filter(|location| !location.is_empty())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use cadence::NopMetricSink;
+    use serde_json::{json, Value};
+
+    use crate::geoheaders::HeaderMapping;
+
+    /// Keys whose values are encoded as the types MaxMind uses for them.
+    /// Other whole numbers are encoded as uint32.
+    const UINT16_KEYS: &[&str] = &[
+        "binary_format_major_version",
+        "binary_format_minor_version",
+        "ip_version",
+        "record_size",
+        "metro_code",
+        "accuracy_radius",
+    ];
+    const UINT64_KEYS: &[&str] = &["build_epoch"];
+
+    fn control(out: &mut Vec<u8>, type_num: u8, size: usize) {
+        let (first, extended) = if type_num <= 7 {
+            (type_num << 5, None)
+        } else {
+            (0, Some(type_num - 7))
+        };
+        let (size_bits, size_bytes) = match size {
+            0..=28 => (size, vec![]),
+            29..=284 => (29, vec![(size - 29) as u8]),
+            285..=65820 => (30, (size - 285).to_be_bytes()[6..].to_vec()),
+            _ => (31, (size - 65821).to_be_bytes()[5..].to_vec()),
+        };
+        out.push(first | size_bits as u8);
+        out.extend(extended);
+        out.extend(size_bytes);
+    }
+
+    fn encode(value: &Value, key: &str, out: &mut Vec<u8>) {
+        match value {
+            Value::String(s) => {
+                control(out, 2, s.len());
+                out.extend(s.as_bytes());
+            }
+            Value::Bool(b) => control(out, 14, *b as usize),
+            Value::Number(n) if n.is_u64() => {
+                let n = n.as_u64().unwrap();
+                let (type_num, bytes) = if UINT16_KEYS.contains(&key) {
+                    (5, n.to_be_bytes()[6..].to_vec())
+                } else if UINT64_KEYS.contains(&key) {
+                    (9, n.to_be_bytes().to_vec())
+                } else {
+                    (6, n.to_be_bytes()[4..].to_vec())
+                };
+                let bytes: Vec<u8> = bytes.into_iter().skip_while(|b| *b == 0).collect();
+                control(out, type_num, bytes.len());
+                out.extend(bytes);
+            }
+            Value::Number(n) => {
+                control(out, 3, 8);
+                out.extend(&n.as_f64().unwrap().to_be_bytes());
+            }
+            Value::Array(items) => {
+                control(out, 11, items.len());
+                for item in items {
+                    encode(item, key, out);
+                }
+            }
+            Value::Object(map) => {
+                control(out, 7, map.len());
+                for (key, value) in map {
+                    encode(&json!(key), "", out);
+                    encode(value, key, out);
+                }
+            }
+            Value::Null => panic!("null can't be encoded"),
+        }
+    }
+
+    /// Build an IPv4 MaxMind database holding a record for each network.
+    pub fn mmdb(database_type: &str, networks: &[(&str, Value)]) -> Vec<u8> {
+        // Each node has a left (0 bit) and right (1 bit) record, pointing at
+        // another node, at data, or at nothing.
+        enum Record {
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes: Vec<[Option<Record>; 2]> = vec![[None, None]];
+        for (i, (network, _)) in networks.iter().enumerate() {
+            let net = network.parse::<ipnet::Ipv4Net>().unwrap();
+            let bits = u32::from(net.addr());
+            let mut node = 0;
+            for depth in 0..net.prefix_len() {
+                let bit = ((bits >> (31 - depth)) & 1) as usize;
+                if depth + 1 == net.prefix_len() {
+                    nodes[node][bit] = Some(Record::Data(i));
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Some(Record::Node(next)) => next,
+                    _ => {
+                        nodes.push([None, None]);
+                        let next = nodes.len() - 1;
+                        nodes[node][bit] = Some(Record::Node(next));
+                        next
+                    }
+                };
+            }
+        }
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for (_, record) in networks {
+            offsets.push(data.len());
+            encode(record, "", &mut data);
+        }
+        let node_count = nodes.len();
+        let mut buf = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match record {
+                    Some(Record::Node(next)) => *next,
+                    Some(Record::Data(i)) => node_count + 16 + offsets[*i],
+                    None => node_count,
+                };
+                buf.extend(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        buf.extend(&[0; 16]);
+        buf.extend(data);
+        buf.extend(b"\xAB\xCD\xEFMaxMind.com");
+        let metadata = json!({
+            "binary_format_major_version": 2,
+            "binary_format_minor_version": 0,
+            "build_epoch": 1_600_000_000,
+            "database_type": database_type,
+            "description": {"en": "Test database"},
+            "ip_version": 4,
+            "languages": ["en"],
+            "node_count": node_count,
+            "record_size": 24,
+        });
+        encode(&metadata, "", &mut buf);
+        buf
+    }
+
+    /// A City database locating 63.245.208.0/24 and 10.0.0.0/8.
+    pub fn city_mmdb(database_type: &str) -> Vec<u8> {
+        mmdb(
+            database_type,
+            &[
+                (
+                    "63.245.208.0/24",
+                    json!({
+                        "city": {"geoname_id": 5_389_489, "names": {
+                            "en": "Sacramento", "ja": "サクラメント", "ru": "Сакраменто"}},
+                        "country": {"geoname_id": 6_252_001, "iso_code": "US", "names": {
+                            "de": "USA", "en": "United States", "fr": "États-Unis"}},
+                        "subdivisions": [{"geoname_id": 5_332_921, "iso_code": "CA", "names": {
+                            "en": "California", "fr": "Californie"}}],
+                        "continent": {"code": "NA", "geoname_id": 6_255_149, "names": {
+                            "en": "North America"}},
+                        "location": {"accuracy_radius": 20, "metro_code": 862,
+                            "time_zone": "America/Los_Angeles"},
+                    }),
+                ),
+                (
+                    "10.0.0.0/8",
+                    json!({"country": {"names": {"en": "Nowhere"}}}),
+                ),
+            ],
+        )
+    }
+
+    pub fn nop_metrics() -> StatsdClient {
+        StatsdClient::from_sink("test", NopMetricSink)
+    }
+
+    /// A client connecting directly from `addr`.
+    pub fn client<'a>(addr: &str, headers: &'a HeaderMap) -> Client<'a> {
+        Client {
+            addr: addr.parse().ok(),
+            via_trusted_proxy: false,
+            headers,
+        }
+    }
+
+    /// The names of the location, in the best of `langs`.
+    fn localize(location: &Location, langs: &Languages) -> Location {
+        let localize = |name: &Option<Name>| {
+            name.as_ref()
+                .and_then(|name| name.localize(langs))
+                .map(Name::Plain)
+        };
+        Location {
+            city: localize(&location.city),
+            region: localize(&location.region),
+            country: localize(&location.country),
+            ..Default::default()
+        }
+    }
+
+    fn location(city: &str, region: &str, country: &str) -> Location {
+        Location {
+            city: Some(city.into()),
+            region: Some(region.into()),
+            country: Some(country.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_providers() {
+        assert_eq!(
+            parse_providers("maxmind, HEADER,"),
+            Ok(vec![ProviderKind::MaxMind, ProviderKind::Header])
+        );
+        assert_eq!(parse_providers(""), Ok(vec![]));
+        assert!(parse_providers("maxmind, gps").is_err());
+        assert!(MaxMindProvider::open("missing.mmdb", &nop_metrics()).is_err());
+    }
+
+    /// Swap a new file in, as the database is mapped.
+    fn replace(path: &Path, contents: &[u8]) {
+        let new = path.with_extension("new");
+        fs::write(&new, contents).unwrap();
+        fs::rename(&new, path).unwrap();
+    }
+
+    #[test]
+    fn test_maxmind_provider() {
+        let log = MozLogger::new_human();
+        let headers = HeaderMap::new();
+        let path = std::env::temp_dir().join(format!("geo-{}.mmdb", std::process::id()));
+        let path_str = path.to_string_lossy().into_owned();
+        replace(&path, &city_mmdb("GeoLite2-City"));
+        let provider = MaxMindProvider::open(&path_str, &nop_metrics()).unwrap();
+        let locate = |ip: &str| provider.locate(&client(ip, &headers), &log);
+        // Every name is kept, to be given in each peer's language.
+        let found = locate("63.245.208.195").unwrap();
+        assert_eq!(
+            localize(&found, &Languages::parse("fr", "en")),
+            location("Sacramento", "Californie", "États-Unis")
+        );
+        assert_eq!(
+            localize(&found, &Languages::parse("ja", "en")).city,
+            Some("サクラメント".into())
+        );
+        assert_eq!(found.country_code, Some("US".to_owned()));
+        assert_eq!(found.region_code, Some("US-CA".to_owned()));
+        assert_eq!(found.continent, Some("NA".to_owned()));
+        assert_eq!(found.time_zone, Some("America/Los_Angeles".to_owned()));
+        assert_eq!(found.accuracy_radius, Some(20));
+        assert!(found.is_complete());
+        assert_eq!(locate("192.168.1.1"), None);
+        assert!(!provider.reload_if_changed(&log));
+
+        // A new database is swapped in.
+        replace(&path, &city_mmdb("GeoIP2-City"));
+        *provider.modified.lock().unwrap() = None;
+        assert!(provider.reload_if_changed(&log));
+        assert_eq!(
+            provider.reader.read().unwrap().metadata.database_type,
+            "GeoIP2-City"
+        );
+
+        // A broken or wrong database leaves the current one in place.
+        let english = Languages::parse("", "en");
+        for contents in &[b"garbage".to_vec(), city_mmdb("GeoLite2-ASN")] {
+            replace(&path, contents);
+            *provider.modified.lock().unwrap() = None;
+            assert!(!provider.reload_if_changed(&log));
+            assert_eq!(
+                localize(&locate("10.1.2.3").unwrap(), &english).country,
+                Some("Nowhere".into())
+            );
+        }
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_handle_city_err() {
+        let log = MozLogger::new_human();
+        assert!(handle_city_err(
+            &log,
+            &MaxMindDBError::InvalidDatabaseError("corrupt".to_owned())
+        ));
+        assert!(!handle_city_err(
+            &log,
+            &MaxMindDBError::AddressNotFoundError("missing".to_owned())
+        ));
+    }
+
+    #[test]
+    fn test_static_provider() {
+        let log = MozLogger::new_human();
+        let headers = HeaderMap::new();
+        let provider: StaticProvider = "
+            # office
+            10.0.0.0/8      United States; California
+            10.1.0.0/16     United States; California; Mountain View
+            192.168.1.1     Canada; ; Toronto
+        "
+        .parse()
+        .unwrap();
+        let locate = |ip: &str| provider.locate(&client(ip, &headers), &log);
+        assert_eq!(
+            locate("10.1.2.3"),
+            Some(location("Mountain View", "California", "United States"))
+        );
+        assert_eq!(
+            locate("10.2.3.4").unwrap().region,
+            Some("California".into())
+        );
+        assert_eq!(locate("10.2.3.4").unwrap().city, None);
+        assert_eq!(locate("192.168.1.1").unwrap().region, None);
+        assert_eq!(locate("192.168.1.2"), None);
+        assert_eq!(locate(""), None);
+        assert!("bogus United States".parse::<StaticProvider>().is_err());
+    }
+
+    #[test]
+    fn test_provider_chain() {
+        let log = MozLogger::new_human();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-client-geo-location"),
+            HeaderValue::from_static("Ontario,Toronto"),
+        );
+        let chain = ProviderChain(vec![
+            Box::new(NoProvider),
+            Box::new(
+                "10.0.0.0/8 United States; California"
+                    .parse::<StaticProvider>()
+                    .unwrap(),
+            ),
+            Box::new("gcp".parse::<HeaderMapping>().unwrap()),
+            Box::new(
+                "10.0.0.0/8 united states; CALIFORNIA; Sacramento"
+                    .parse::<StaticProvider>()
+                    .unwrap(),
+            ),
+        ]);
+        let mut proxied = client("10.0.0.1", &headers);
+        proxied.via_trusted_proxy = true;
+        // Each provider fills in what the ones before it left out, but only
+        // if it puts the client in the same place. The headers don't name a
+        // country, let alone the same region.
+        assert_eq!(
+            chain.locate(&proxied, &log),
+            Some(location("Sacramento", "California", "United States"))
+        );
+        proxied.addr = "1.2.3.4".parse().ok();
+        assert_eq!(
+            chain.locate(&proxied, &log),
+            Some(Location {
+                city: Some("Toronto".into()),
+                region: Some("Ontario".into()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(ProviderChain(vec![]).locate(&proxied, &log), None);
+    }
+
+    #[test]
+    fn test_fill() {
+        let sacramento = Location {
+            country_code: Some("US".to_owned()),
+            region_code: Some("US-CA".to_owned()),
+            continent: Some("NA".to_owned()),
+            time_zone: Some("America/Los_Angeles".to_owned()),
+            accuracy_radius: Some(20),
+            ..location("Sacramento", "California", "United States")
+        };
+        // One provider's time zone isn't paired with another's city.
+        let mut found = Location {
+            country_code: Some("US".to_owned()),
+            time_zone: Some("America/Los_Angeles".to_owned()),
+            ..Default::default()
+        };
+        found.fill(sacramento.clone());
+        assert_eq!(
+            found,
+            Location {
+                region: Some("California".into()),
+                country_code: Some("US".to_owned()),
+                region_code: Some("US-CA".to_owned()),
+                time_zone: Some("America/Los_Angeles".to_owned()),
+                ..Default::default()
+            }
+        );
+        // Nothing is taken from another country.
+        let mut found = Location {
+            country_code: Some("CA".to_owned()),
+            ..Default::default()
+        };
+        found.fill(sacramento.clone());
+        assert_eq!(found.region, None);
+        assert_eq!(found.time_zone, None);
+
+        // Nor from a location that leaves out a part already found.
+        let mut found = Location {
+            country: Some("United States".into()),
+            ..Default::default()
+        };
+        found.fill(Location {
+            city: Some("Seattle".into()),
+            ..Default::default()
+        });
+        assert_eq!(found.city, None);
+        // Names are compared where there are no codes.
+        let mut found = Location {
+            region: Some("Ontario".into()),
+            city: Some("Toronto".into()),
+            ..Default::default()
+        };
+        found.fill(sacramento.clone());
+        assert_eq!(found.country, None);
+
+        let mut found = Location::default();
+        found.fill(sacramento.clone());
+        assert_eq!(found, sacramento);
+        assert!(found.is_complete());
+    }
+
+    #[test]
+    fn test_provider_order() {
+        let log = MozLogger::new_human();
+        let path = std::env::temp_dir().join(format!("geo-order-{}.txt", std::process::id()));
+        fs::write(&path, "10.0.0.0/8 United States; California; Sacramento\n").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-client-geo-location"),
+            HeaderValue::from_static("Ontario,Toronto"),
+        );
+        let mut proxied = client("10.0.0.1", &headers);
+        proxied.via_trusted_proxy = true;
+        let locate = |geo_provider: &str, geo_headers: &str| {
+            let settings = Settings {
+                geo_provider: geo_provider.to_owned(),
+                geo_headers: geo_headers.to_owned(),
+                geo_static_file: path.to_string_lossy().into_owned(),
+                ..Default::default()
+            };
+            from_settings(&settings, &log, &nop_metrics())
+                .unwrap()
+                .locate(&proxied, &log)
+        };
+        // Fallback mappings are asked where `header` is listed.
+        let toronto = Some(Location {
+            city: Some("Toronto".into()),
+            region: Some("Ontario".into()),
+            ..Default::default()
+        });
+        assert_eq!(locate("header, static", "gcp"), toronto);
+        assert_eq!(
+            locate("static, header", "gcp"),
+            Some(location("Sacramento", "California", "United States"))
+        );
+        // Preferred ones come first wherever it is.
+        assert_eq!(locate("static, header", "gcp prefer"), toronto);
+        fs::remove_file(&path).ok();
+    }
+}
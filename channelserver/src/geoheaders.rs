@@ -0,0 +1,339 @@
+//! Locations found by a CDN or load balancer and passed along in request
+//! headers.
+//!
+//! `geo_headers` is a comma delimited list of mappings. Each is either the
+//! name of a preset:
+//!
+//! * `gcp`: `X-Client-Geo-Location`, holding "region,city" (a Google Cloud
+//!   load balancer custom header set to
+//!   `{client_region_subdivision},{client_city}`)
+//! * `cloudfront`: `CloudFront-Viewer-Country`,
+//!   `CloudFront-Viewer-Country-Name`, `CloudFront-Viewer-Country-Region`,
+//!   `CloudFront-Viewer-Country-Region-Name`, `CloudFront-Viewer-City` and
+//!   `CloudFront-Viewer-Time-Zone`
+//! * `fastly`: `Fastly-Geo-Country-Code`, `Fastly-Geo-Country-Name`,
+//!   `Fastly-Geo-Region` (a subdivision code) and `Fastly-Geo-City`, set in
+//!   VCL from the `client.geo` variables
+//! * `cloudflare`: `CF-IPCountry` (a country code) and, if visitor location
+//!   headers are added, `CF-Region`, `CF-Region-Code`, `CF-IPCity` and
+//!   `CF-Timezone`
+//!
+//! or a header followed by the fields its comma delimited value holds, in
+//! order, e.g. `X-Geo=country+region+city`. Besides the names (`city`,
+//! `region`, `country`), a header may hold an ISO 3166-1 `country_code`, an
+//! ISO 3166-2 `region_code` (the subdivision part alone, e.g. `CA`, is
+//! qualified with the country code) or an IANA `time_zone`.
+//!
+//! Either may be followed by `prefer`, to use the header over what the other
+//! providers find, or `fallback` (the default), to only fill in what they
+//! leave out, e.g. `cloudflare prefer, X-Geo=city fallback`.
+//!
+//! Clients can set these headers themselves, so they are only believed when
+//! the connection comes from a trusted proxy.
+use std::str::FromStr;
+
+use actix_web::http::header::HeaderName;
+use slog::debug;
+
+use crate::geo::{Client, GeoProvider, Location, Name};
+use crate::logging::MozLogger;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    City,
+    Region,
+    Country,
+    CountryCode,
+    RegionCode,
+    TimeZone,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "city" => Ok(Field::City),
+            "region" => Ok(Field::Region),
+            "country" => Ok(Field::Country),
+            "country_code" => Ok(Field::CountryCode),
+            "region_code" => Ok(Field::RegionCode),
+            "time_zone" => Ok(Field::TimeZone),
+            _ => Err(format!("Unknown location field: {:?}", s.trim())),
+        }
+    }
+}
+
+/// Whether a mapping is asked before or after the other providers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    Prefer,
+    Fallback,
+}
+
+/// A header, and the fields its comma delimited value holds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GeoHeader {
+    name: HeaderName,
+    fields: Vec<Field>,
+    /// Values meaning the location isn't known
+    unknown: &'static [&'static str],
+}
+
+impl GeoHeader {
+    fn new(name: &'static str, fields: &[Field]) -> Self {
+        Self {
+            name: HeaderName::from_static(name),
+            fields: fields.to_vec(),
+            unknown: &[],
+        }
+    }
+}
+
+fn preset(name: &str) -> Option<Vec<GeoHeader>> {
+    use Field::*;
+    let headers = match name.to_lowercase().as_str() {
+        "gcp" => vec![GeoHeader::new("x-client-geo-location", &[Region, City])],
+        "cloudfront" => vec![
+            GeoHeader::new("cloudfront-viewer-country", &[CountryCode]),
+            GeoHeader::new("cloudfront-viewer-country-name", &[Country]),
+            GeoHeader::new("cloudfront-viewer-country-region", &[RegionCode]),
+            GeoHeader::new("cloudfront-viewer-country-region-name", &[Region]),
+            GeoHeader::new("cloudfront-viewer-city", &[City]),
+            GeoHeader::new("cloudfront-viewer-time-zone", &[TimeZone]),
+        ],
+        "fastly" => vec![
+            GeoHeader::new("fastly-geo-country-code", &[CountryCode]),
+            GeoHeader::new("fastly-geo-country-name", &[Country]),
+            // `client.geo.region` is the subdivision code, e.g. "CA".
+            GeoHeader::new("fastly-geo-region", &[RegionCode]),
+            GeoHeader::new("fastly-geo-city", &[City]),
+        ],
+        "cloudflare" => vec![
+            GeoHeader {
+                // "XX" for unknown, "T1" for Tor.
+                unknown: &["XX", "T1"],
+                ..GeoHeader::new("cf-ipcountry", &[CountryCode])
+            },
+            GeoHeader::new("cf-region", &[Region]),
+            GeoHeader::new("cf-region-code", &[RegionCode]),
+            GeoHeader::new("cf-ipcity", &[City]),
+            GeoHeader::new("cf-timezone", &[TimeZone]),
+        ],
+        _ => return None,
+    };
+    Some(headers)
+}
+
+/// A preset or custom set of headers, and whether to prefer them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HeaderMapping {
+    name: String,
+    headers: Vec<GeoHeader>,
+    pub mode: Mode,
+}
+
+impl FromStr for HeaderMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words
+            .next()
+            .ok_or_else(|| "Empty geo header mapping".to_owned())?;
+        let mode = match words.next().map(str::to_lowercase).as_deref() {
+            None | Some("fallback") => Mode::Fallback,
+            Some("prefer") => Mode::Prefer,
+            Some(other) => return Err(format!("Unknown mode {:?} for {:?}", other, name)),
+        };
+        if let Some(extra) = words.next() {
+            return Err(format!("Unexpected {:?} after {:?}", extra, name));
+        }
+        let headers = match name.split_once('=') {
+            Some((header, fields)) => {
+                let header = HeaderName::from_bytes(header.as_bytes())
+                    .map_err(|_| format!("Invalid header name: {:?}", header))?;
+                let fields = fields
+                    .split('+')
+                    .map(str::parse)
+                    .collect::<Result<Vec<Field>, String>>()?;
+                vec![GeoHeader {
+                    name: header,
+                    fields,
+                    unknown: &[],
+                }]
+            }
+            None => preset(name).ok_or_else(|| format!("Unknown geo header preset: {:?}", name))?,
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            headers,
+            mode,
+        })
+    }
+}
+
+/// Parse the comma delimited `geo_headers` list.
+pub fn parse_mappings(list: &str) -> Result<Vec<HeaderMapping>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl GeoProvider for HeaderMapping {
+    fn locate(&self, client: &Client, log: &MozLogger) -> Option<Location> {
+        if !client.via_trusted_proxy {
+            if self
+                .headers
+                .iter()
+                .any(|header| client.headers.contains_key(&header.name))
+            {
+                debug!(log.log, "Ignoring geo headers from an untrusted peer"; "mapping" => &self.name);
+            }
+            return None;
+        }
+        let mut location = Location::default();
+        for header in &self.headers {
+            let value = match client.headers.get(&header.name).map(|v| v.to_str()) {
+                Some(Ok(value)) => value,
+                _ => continue,
+            };
+            for (field, value) in header.fields.iter().zip(value.split(',').map(str::trim)) {
+                if value.is_empty() || header.unknown.contains(&value) {
+                    continue;
+                }
+                match field {
+                    Field::City => location.city = Some(Name::from(value)),
+                    Field::Region => location.region = Some(Name::from(value)),
+                    Field::Country => location.country = Some(Name::from(value)),
+                    Field::CountryCode => location.country_code = Some(value.to_uppercase()),
+                    Field::RegionCode => location.region_code = Some(value.to_uppercase()),
+                    Field::TimeZone => location.time_zone = Some(value.to_owned()),
+                }
+            }
+        }
+        // Region codes are ISO 3166-2, e.g. "US-CA", but headers often only
+        // hold the subdivision part.
+        if let Some(code) = location.region_code.take() {
+            location.region_code = if code.contains('-') {
+                Some(code)
+            } else {
+                location
+                    .country_code
+                    .as_ref()
+                    .map(|country| format!("{}-{}", country, code))
+            };
+        }
+        if location.is_empty() {
+            return None;
+        }
+        Some(location)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix_web::http::{header::HeaderValue, HeaderMap};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    fn locate(mapping: &str, headers: &HeaderMap, trusted: bool) -> Option<Location> {
+        let client = Client {
+            addr: None,
+            via_trusted_proxy: trusted,
+            headers,
+        };
+        let mapping: HeaderMapping = mapping.parse().unwrap();
+        mapping.locate(&client, &MozLogger::new_human())
+    }
+
+    #[test]
+    fn test_parse_mappings() {
+        let mappings =
+            parse_mappings("gcp, cloudflare prefer, X-Geo=country+city fallback,").unwrap();
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0].mode, Mode::Fallback);
+        assert_eq!(mappings[1].mode, Mode::Prefer);
+        assert_eq!(
+            mappings[2].headers,
+            vec![GeoHeader {
+                name: HeaderName::from_static("x-geo"),
+                fields: vec![Field::Country, Field::City],
+                unknown: &[],
+            }]
+        );
+        assert!(parse_mappings("akamai").is_err());
+        assert!(parse_mappings("gcp sometimes").is_err());
+        assert!(parse_mappings("X-Geo=planet").is_err());
+        assert!(parse_mappings("X Geo=city").is_err());
+    }
+
+    #[test]
+    fn test_presets() {
+        let gcp = headers(&[("x-client-geo-location", "California,Mountain View")]);
+        assert_eq!(
+            locate("gcp", &gcp, true),
+            Some(Location {
+                city: Some("Mountain View".into()),
+                region: Some("California".into()),
+                ..Default::default()
+            })
+        );
+        let cloudfront = headers(&[
+            ("cloudfront-viewer-country", "US"),
+            ("cloudfront-viewer-country-name", "United States"),
+            ("cloudfront-viewer-country-region", "WA"),
+            ("cloudfront-viewer-city", "Seattle"),
+            ("cloudfront-viewer-time-zone", "America/Los_Angeles"),
+        ]);
+        let found = locate("cloudfront", &cloudfront, true).unwrap();
+        assert_eq!(found.country, Some("United States".into()));
+        assert_eq!(found.country_code, Some("US".to_owned()));
+        assert_eq!(found.region_code, Some("US-WA".to_owned()));
+        assert_eq!(found.time_zone, Some("America/Los_Angeles".to_owned()));
+        assert_eq!(found.city, Some("Seattle".into()));
+        assert_eq!(found.region, None);
+
+        // Cloudflare gives a country code, not a name.
+        let cloudflare = headers(&[("cf-ipcountry", "DE")]);
+        let found = locate("cloudflare", &cloudflare, true).unwrap();
+        assert_eq!(found.country_code, Some("DE".to_owned()));
+        assert_eq!(found.country, None);
+        let tor = headers(&[("cf-ipcountry", "T1")]);
+        assert_eq!(locate("cloudflare", &tor, true), None);
+        assert_eq!(locate("fastly", &cloudflare, true), None);
+    }
+
+    #[test]
+    fn test_untrusted() {
+        let spoofed = headers(&[("x-client-geo-location", "California,Mountain View")]);
+        assert_eq!(locate("gcp", &spoofed, false), None);
+        let custom = headers(&[("x-geo", "Canada, ,Toronto")]);
+        let found = locate("X-Geo=country+region+city", &custom, true).unwrap();
+        assert_eq!(found.region, None);
+        assert_eq!(found.city, Some("Toronto".into()));
+        assert_eq!(locate("X-Geo=country+region+city", &custom, false), None);
+
+        // A subdivision code can't be qualified without the country's.
+        let codes = headers(&[("x-geo", "ca,on,America/Toronto")]);
+        let found = locate("X-Geo=country_code+region_code+time_zone", &codes, true).unwrap();
+        assert_eq!(found.country_code, Some("CA".to_owned()));
+        assert_eq!(found.region_code, Some("CA-ON".to_owned()));
+        assert_eq!(found.time_zone, Some("America/Toronto".to_owned()));
+        let found = locate("X-Geo=region_code+city", &codes, true).unwrap();
+        assert_eq!(found.region_code, None);
+    }
+}
@@ -0,0 +1,333 @@
+//! Allow and deny lists for connecting clients.
+//!
+//! Lists are CIDR ranges (single addresses are accepted as well), drawn from
+//! `ip_allow_list` and `ip_deny_list` and, optionally, from `ip_filter_file`.
+//! An address on the allow list is always admitted. Otherwise, it is refused
+//! if it is on the deny list. Denying `0.0.0.0/0, ::/0` turns the allow list
+//! into the only way in.
+//!
+//! The filter file holds one `allow <range>` or `deny <range>` entry per line
+//! (`#` starts a comment), and is re-read whenever it changes, so that
+//! abusive networks can be blocked without a restart.
+//!
+//! Once any list is set, clients whose address can't be worked out are
+//! refused, rather than let past the lists.
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use ipnet::IpNet;
+use slog::{debug, error, info};
+
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// Parse a comma delimited list of CIDR ranges. Single addresses are treated
+/// as a range of one. Unparsable entries are logged and skipped.
+pub fn parse_ip_list(list: &str, log: &MozLogger) -> Vec<IpNet> {
+    let mut result = Vec::new();
+    for entry in list.split(',') {
+        if let Some(net) = parse_ip_net(entry, log) {
+            result.push(net);
+        }
+    }
+    result
+}
+
+/// Describe each entry of a comma delimited list of CIDR ranges that can't
+/// be parsed.
+pub fn check_ip_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            to_ip_net(entry)
+                .err()
+                .map(|err| format!("{:?} is not an IP address or range: {}", entry, err))
+        })
+        .collect()
+}
+
+fn to_ip_net(entry: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    if !entry.contains('/') {
+        if let Ok(addr) = entry.parse::<IpAddr>() {
+            return Ok(IpNet::from(addr));
+        }
+    }
+    entry.parse::<IpNet>()
+}
+
+fn parse_ip_net(entry: &str, log: &MozLogger) -> Option<IpNet> {
+    let entry = entry.trim();
+    if entry.is_empty() {
+        return None;
+    }
+    match to_ip_net(entry) {
+        Ok(net) => {
+            if !entry.contains('/') {
+                debug!(log.log, "Fixing single address {}", entry);
+            }
+            Some(net)
+        }
+        Err(err) => {
+            error!(
+                log.log,
+                r#"Ignoring unparsable IP address "{} {:?}"#, entry, err
+            );
+            None
+        }
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(path))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct IpLists {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpLists {
+    fn is_allowed(&self, addr: &IpAddr) -> bool {
+        if self.allow.iter().any(|range| range.contains(addr)) {
+            return true;
+        }
+        !self.deny.iter().any(|range| range.contains(addr))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Add the `allow`/`deny` entries from the contents of a filter file.
+    fn extend_from_file(&mut self, contents: &str, log: &MozLogger) {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let list = match parts.next() {
+                Some("allow") => &mut self.allow,
+                Some("deny") => &mut self.deny,
+                _ => {
+                    error!(log.log, "Ignoring unknown IP filter rule {:?}", line);
+                    continue;
+                }
+            };
+            for entry in parts {
+                if let Some(net) = parse_ip_net(entry, log) {
+                    list.push(net);
+                }
+            }
+        }
+    }
+}
+
+/// Shared allow/deny lists. Clones refer to the same lists, so a reload is
+/// seen by every worker.
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    lists: Arc<RwLock<IpLists>>,
+    base: IpLists,
+    file: Option<String>,
+    modified: Arc<RwLock<Option<SystemTime>>>,
+    log: MozLogger,
+}
+
+impl IpFilter {
+    pub fn new(settings: &Settings, log: &MozLogger) -> Self {
+        let base = IpLists {
+            allow: parse_ip_list(&settings.ip_allow_list, log),
+            deny: parse_ip_list(&settings.ip_deny_list, log),
+        };
+        let file = if settings.ip_filter_file.is_empty() {
+            None
+        } else {
+            Some(settings.ip_filter_file.clone())
+        };
+        let filter = Self {
+            lists: Arc::new(RwLock::new(base.clone())),
+            base,
+            file,
+            modified: Arc::new(RwLock::new(None)),
+            log: log.clone(),
+        };
+        if let Some(ref path) = filter.file {
+            *filter.modified.write().unwrap_or_else(|e| e.into_inner()) = modified_time(path);
+            filter.load(path);
+        }
+        filter
+    }
+
+    /// Is this address allowed to connect?
+    pub fn is_allowed(&self, addr: &IpAddr) -> bool {
+        match self.lists.read() {
+            Ok(lists) => lists.is_allowed(addr),
+            Err(poisoned) => poisoned.into_inner().is_allowed(addr),
+        }
+    }
+
+    /// Is a client at `addr` allowed to connect? A client with no known
+    /// address is only allowed when there are no lists to check it against.
+    pub fn admits(&self, addr: Option<&IpAddr>) -> bool {
+        match addr {
+            Some(addr) => self.is_allowed(addr),
+            None => self
+                .lists
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_empty(),
+        }
+    }
+
+    /// Re-read the filter file if it has been modified since it was last
+    /// read. Returns true if the lists were replaced.
+    pub fn reload_if_changed(&self) -> bool {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return false,
+        };
+        let modified = modified_time(path);
+        {
+            let mut last = self.modified.write().unwrap_or_else(|e| e.into_inner());
+            if *last == modified {
+                return false;
+            }
+            *last = modified;
+        }
+        self.load(path)
+    }
+
+    fn load(&self, path: &str) -> bool {
+        let mut lists = self.base.clone();
+        match fs::read_to_string(path) {
+            Ok(contents) => lists.extend_from_file(&contents, &self.log),
+            Err(err) => {
+                // Keep whatever we had before.
+                error!(
+                    self.log.log,
+                    "Could not read IP filter file {}: {:?}", path, err
+                );
+                return false;
+            }
+        }
+        info!(
+            self.log.log,
+            "Loaded IP filter";
+            "file" => path,
+            "allow" => lists.allow.len(),
+            "deny" => lists.deny.len(),
+        );
+        *self.lists.write().unwrap_or_else(|e| e.into_inner()) = lists;
+        true
+    }
+
+    /// Check the filter file for changes every `interval`.
+    pub fn watch(&self, interval: Duration) {
+        if self.file.is_none() || interval == Duration::from_secs(0) {
+            return;
+        }
+        let filter = self.clone();
+        actix_rt::spawn(async move {
+            let mut ticker = actix_rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                filter.reload_if_changed();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_ip_list() {
+        let log = MozLogger::new_human();
+        let list = parse_ip_list(" 10.0.0.0/8,192.168.1.1, bogus,,2001:db8::1 ", &log);
+        assert_eq!(
+            list,
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "192.168.1.1/32".parse::<IpNet>().unwrap(),
+                "2001:db8::1/128".parse::<IpNet>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_ip_list() {
+        assert!(check_ip_list(" 10.0.0.0/8,192.168.1.1,,2001:db8::1 ").is_empty());
+        let problems = check_ip_list("10.0.0.0/8, bogus, 10.0.0.0/33");
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("bogus"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let log = MozLogger::new_human();
+        let settings = Settings {
+            ip_allow_list: "1.2.3.4".to_owned(),
+            ip_deny_list: "1.2.3.0/24, 2001:db8::/32".to_owned(),
+            ..Default::default()
+        };
+        let filter = IpFilter::new(&settings, &log);
+        assert!(filter.is_allowed(&ip("1.2.3.4")));
+        assert!(!filter.is_allowed(&ip("1.2.3.5")));
+        assert!(!filter.is_allowed(&ip("2001:db8::5")));
+        assert!(filter.is_allowed(&ip("4.3.2.1")));
+
+        // Unknown addresses fail closed once there is a list.
+        assert!(!filter.admits(None));
+        assert!(filter.admits(Some(&ip("4.3.2.1"))));
+
+        let settings = Settings::default();
+        let filter = IpFilter::new(&settings, &log);
+        assert!(filter.is_allowed(&ip("1.2.3.4")));
+        assert!(filter.admits(None));
+    }
+
+    #[test]
+    fn test_filter_file() {
+        let log = MozLogger::new_human();
+        let path = std::env::temp_dir().join(format!("ipfilter-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# only the office\ndeny 0.0.0.0/0 ::/0\nallow 5.6.7.0/24 # office\n",
+        )
+        .unwrap();
+        let settings = Settings {
+            ip_deny_list: "9.9.9.9".to_owned(),
+            ip_filter_file: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let filter = IpFilter::new(&settings, &log);
+        assert!(filter.is_allowed(&ip("5.6.7.8")));
+        assert!(!filter.is_allowed(&ip("1.1.1.1")));
+        assert!(!filter.is_allowed(&ip("::1")));
+        // Unchanged file is not re-read.
+        assert!(!filter.reload_if_changed());
+
+        fs::write(&path, "deny 1.1.1.1\n").unwrap();
+        // Make sure the modification time moves even on coarse filesystems.
+        *filter.modified.write().unwrap() = None;
+        assert!(filter.reload_if_changed());
+        assert!(filter.is_allowed(&ip("5.6.7.8")));
+        assert!(!filter.is_allowed(&ip("1.1.1.1")));
+        // Settings lists are kept across reloads.
+        assert!(!filter.is_allowed(&ip("9.9.9.9")));
+        fs::remove_file(&path).ok();
+    }
+}
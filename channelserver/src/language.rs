@@ -0,0 +1,250 @@
+//! `Accept-Language` negotiation.
+//!
+//! Localized names are picked with the "lookup" scheme of RFC 4647, section
+//! 3.4: the client's language ranges are tried from most to least preferred,
+//! each one progressively shortened (`zh-Hant-TW`, then `zh-Hant`, then `zh`)
+//! until a name is found. Ranges with `q=0` rule out every tag they match,
+//! and `*` stands for the server's default language.
+//!
+//! MaxMind only has names for a region specific variant of some languages
+//! (`pt-BR`, `zh-CN`), which plain lookup would never reach from `pt` or
+//! `zh-TW`. Once a range and its prefixes find nothing, a variant of the
+//! same language is taken before moving on to the next range.
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// Quality values are kept in thousandths, e.g. 500 for `q=0.5`.
+const MAX_QUALITY: u16 = 1000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct LanguageRange {
+    /// Lower case tag, or `*`
+    tag: String,
+    quality: u16,
+}
+
+/// A client's language preferences, from its `Accept-Language` header.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Languages {
+    /// Acceptable ranges, most preferred first
+    ranges: Vec<LanguageRange>,
+    /// Ranges the client refused with `q=0`
+    excluded: Vec<String>,
+    /// Lower case tag tried once the ranges run out
+    default: String,
+}
+
+/// Is this a well formed language range, e.g. `en`, `zh-Hant-TW` or `*`?
+fn is_range(tag: &str) -> bool {
+    if tag == "*" {
+        return true;
+    }
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let valid = |subtag: &str, alphanumeric: bool| {
+        (1..=8).contains(&subtag.len())
+            && subtag
+                .chars()
+                .all(|c| c.is_ascii_alphabetic() || (alphanumeric && c.is_ascii_digit()))
+    };
+    valid(primary, false) && subtags.all(|subtag| valid(subtag, true))
+}
+
+/// Parse an RFC 7231 qvalue, "0" to "1" with at most three decimals.
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (value, ""),
+    };
+    if fraction.len() > 3 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(MAX_QUALITY),
+        _ => None,
+    }
+}
+
+/// Does `range` match `tag` under RFC 4647 basic filtering, i.e. is it the
+/// tag or one of its prefixes?
+fn matches(range: &str, tag: &str) -> bool {
+    range == "*"
+        || tag == range
+        || (tag.starts_with(range) && tag.as_bytes().get(range.len()) == Some(&b'-'))
+}
+
+/// The progressively shorter forms of a range tried by lookup. A singleton
+/// (e.g. the `x` of a private use tag) is never left at the end.
+fn truncations(range: &str) -> Vec<&str> {
+    let mut forms = vec![range];
+    let mut current = range;
+    while let Some(pos) = current.rfind('-') {
+        current = &current[..pos];
+        if current.rfind('-').map(|pos| current.len() - pos - 1) == Some(1) {
+            continue;
+        }
+        forms.push(current);
+    }
+    forms
+}
+
+impl Languages {
+    /// Parse an `Accept-Language` header. Malformed entries are skipped.
+    pub fn parse(header: &str, default: &str) -> Self {
+        let mut languages = Self {
+            default: default.trim().to_lowercase(),
+            ..Default::default()
+        };
+        for entry in header.split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let tag = parts.next().unwrap_or_default().to_lowercase();
+            if !is_range(&tag) {
+                continue;
+            }
+            let mut quality = Some(MAX_QUALITY);
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = parse_quality(value.trim());
+                    }
+                }
+            }
+            match quality {
+                None => continue,
+                Some(0) => languages.excluded.push(tag),
+                Some(quality) => languages.ranges.push(LanguageRange { tag, quality }),
+            }
+        }
+        // Stable, so equally weighted ranges keep the client's order.
+        languages.ranges.sort_by_key(|range| Reverse(range.quality));
+        languages
+    }
+
+    fn is_excluded(&self, tag: &str) -> bool {
+        self.excluded
+            .iter()
+            .any(|range| range != "*" && matches(range, tag))
+    }
+
+    /// The best of the `available` tags for this client, if any will do.
+    pub fn lookup<'a, I>(&self, available: I) -> Option<&'a str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let available: Vec<(&str, String)> = available
+            .into_iter()
+            .map(|tag| (tag, tag.to_lowercase()))
+            .filter(|(_, lower)| !self.is_excluded(lower))
+            .collect();
+        let exact = |wanted: &str| {
+            available
+                .iter()
+                .find(|(_, lower)| lower == wanted)
+                .map(|(tag, _)| *tag)
+        };
+        let variant = |wanted: &str| {
+            let language = wanted.split('-').next().unwrap_or_default();
+            available
+                .iter()
+                .find(|(_, lower)| matches(language, lower))
+                .map(|(tag, _)| *tag)
+        };
+        let find = |wanted: &str| {
+            truncations(wanted)
+                .into_iter()
+                .find_map(exact)
+                .or_else(|| variant(wanted))
+        };
+        for range in &self.ranges {
+            if range.tag == "*" {
+                // The default, or else anything the client hasn't refused.
+                return find(&self.default).or_else(|| available.first().map(|(tag, _)| *tag));
+            }
+            if let Some(tag) = find(&range.tag) {
+                return Some(tag);
+            }
+        }
+        find(&self.default)
+    }
+
+    /// Pick the name in the best language from localized `names`.
+    pub fn choose(&self, names: &BTreeMap<String, String>) -> Option<String> {
+        self.lookup(names.keys().map(String::as_str))
+            .and_then(|tag| names.get(tag))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The locales GeoLite2 databases have names in.
+    const MAXMIND: &[&str] = &["de", "en", "es", "fr", "ja", "pt-BR", "ru", "zh-CN"];
+
+    fn lookup(header: &str) -> Option<&'static str> {
+        Languages::parse(header, "en").lookup(MAXMIND.iter().copied())
+    }
+
+    #[test]
+    fn test_parse() {
+        let languages = Languages::parse("en-US,es;q=0.1, EN;q=0.5,*;q=0.2, fr;q=0", "en");
+        let tags: Vec<&str> = languages.ranges.iter().map(|r| r.tag.as_str()).collect();
+        assert_eq!(tags, vec!["en-us", "en", "*", "es"]);
+        assert_eq!(languages.excluded, vec!["fr"]);
+
+        // Malformed entries are dropped, not guessed at.
+        let languages =
+            Languages::parse("-, de;q=2, ja;q=0.0001, 🙄💩, ru;q=abc, es;q=1.000", "en");
+        let tags: Vec<&str> = languages.ranges.iter().map(|r| r.tag.as_str()).collect();
+        assert_eq!(tags, vec!["es"]);
+        assert_eq!(parse_quality("0.8"), Some(800));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("1.5"), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("fr-CH, fr;q=0.9, en;q=0.8"), Some("fr"));
+        assert_eq!(lookup("es;q=0.1, de;q=0.5"), Some("de"));
+        assert_eq!(lookup("pt-BR"), Some("pt-BR"));
+        assert_eq!(lookup("zh-CN,zh;q=0.9"), Some("zh-CN"));
+        // Script subtags and three letter codes fall back by subtag.
+        assert_eq!(lookup("zh-Hans-CN"), Some("zh-CN"));
+        assert_eq!(lookup("ja-Latn-JP-x-foo"), Some("ja"));
+        assert_eq!(lookup("gsw-CH, de;q=0.8"), Some("de"));
+        // A variant of the language beats the next language down.
+        assert_eq!(lookup("pt-PT, en;q=0.5"), Some("pt-BR"));
+        assert_eq!(lookup("zh-TW, en;q=0.5"), Some("zh-CN"));
+        // Nothing acceptable falls back to the default.
+        assert_eq!(lookup("tlh"), Some("en"));
+        assert_eq!(lookup(""), Some("en"));
+        assert_eq!(lookup("🙄💩"), Some("en"));
+    }
+
+    #[test]
+    fn test_wildcards_and_exclusions() {
+        assert_eq!(lookup("tlh, *;q=0.5"), Some("en"));
+        // The default is refused, so any other language will do.
+        assert_eq!(lookup("tlh, *;q=0.5, en;q=0"), Some("de"));
+        assert_eq!(lookup("en;q=0, de;q=0"), None);
+        assert_eq!(lookup("en-GB, en;q=0"), None);
+        assert_eq!(lookup("zh;q=0, zh-CN, ja;q=0.5"), Some("ja"));
+        let languages = Languages::parse("", "pt-PT");
+        assert_eq!(languages.lookup(MAXMIND.iter().copied()), Some("pt-BR"));
+    }
+
+    #[test]
+    fn test_choose() {
+        let mut names = BTreeMap::new();
+        names.insert("de".to_owned(), "Kalifornien".to_owned());
+        names.insert("en".to_owned(), "California".to_owned());
+        names.insert("zh-CN".to_owned(), "加利福尼亚州".to_owned());
+        let languages = Languages::parse("zh-Hans, en;q=0.5", "en");
+        assert_eq!(languages.choose(&names), Some("加利福尼亚州".to_owned()));
+        let languages = Languages::parse("fr", "en");
+        assert_eq!(languages.choose(&names), Some("California".to_owned()));
+    }
+}
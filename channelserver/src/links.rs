@@ -0,0 +1,170 @@
+//! Links handed to clients in the welcome message.
+//!
+//! `public_url` is the externally visible base of the server (e.g.
+//! `wss://pair.example.com`), used to give clients an absolute websocket URL
+//! for the channel. `http(s)` base URLs are turned into `ws(s)` ones.
+//!
+//! `deep_link_template` builds an additional link, e.g. to a pairing page.
+//! `{channelid}`, `{link}` and `{url}` in the template are replaced with the
+//! channel ID, the channel's path and its absolute websocket URL. The URL is
+//! percent-encoded, so that it can be passed in a query string or fragment,
+//! and is only available when `public_url` is set.
+use serde_json::{json, Value};
+
+use crate::settings::Settings;
+
+/// Turn a `public_url` into a websocket base URL without a trailing slash.
+pub fn ws_base(public_url: &str) -> Result<String, String> {
+    let url = public_url.trim().trim_end_matches('/');
+    let (scheme, rest) = match url.find("://") {
+        Some(pos) => (&url[..pos], &url[pos + 3..]),
+        None => return Err(format!("Missing scheme: {:?}", public_url)),
+    };
+    let scheme = match scheme.to_lowercase().as_str() {
+        "ws" | "http" => "ws",
+        "wss" | "https" => "wss",
+        _ => return Err(format!("Unsupported scheme: {:?}", public_url)),
+    };
+    if rest.is_empty() || rest.contains('?') || rest.contains('#') {
+        return Err(format!("Invalid public URL: {:?}", public_url));
+    }
+    Ok(format!("{}://{}", scheme, rest))
+}
+
+/// Check a `deep_link_template`, for a server with the given `public_url`.
+pub fn check_template(template: &str, public_url: &str) -> Result<(), String> {
+    if !template.contains("{channelid}") && !template.contains("{url}") {
+        return Err(format!(
+            "Template refers to neither {{channelid}} nor {{url}}: {:?}",
+            template
+        ));
+    }
+    if template.contains("{url}") && public_url.is_empty() {
+        return Err(format!(
+            "Template refers to {{url}}, but public_url isn't set: {:?}",
+            template
+        ));
+    }
+    Ok(())
+}
+
+/// Percent-encode everything but the RFC 3986 unreserved characters.
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Links {
+    base: Option<String>,
+    template: Option<String>,
+}
+
+impl Links {
+    /// Bad values are left out. `Settings::validate` reports them.
+    pub fn new(settings: &Settings) -> Self {
+        let base = if settings.public_url.is_empty() {
+            None
+        } else {
+            ws_base(&settings.public_url).ok()
+        };
+        let template = if settings.deep_link_template.is_empty() {
+            None
+        } else {
+            Some(settings.deep_link_template.clone())
+        };
+        Self { base, template }
+    }
+
+    /// The welcome message telling a client about its channel.
+    pub fn welcome(&self, channel_id: &str, token: &str) -> Value {
+        let link = format!("/v1/ws/{}", channel_id);
+        let mut welcome = json!({
+            "link": link,
+            "channelid": channel_id,
+            "token": token,
+        });
+        let url = self.base.as_ref().map(|base| format!("{}{}", base, link));
+        if let Some(ref url) = url {
+            welcome["url"] = json!(url);
+        }
+        if let Some(ref template) = self.template {
+            welcome["deep_link"] = json!(template
+                .replace("{channelid}", channel_id)
+                .replace("{link}", &link)
+                .replace(
+                    "{url}",
+                    &encode_component(url.as_deref().unwrap_or_default())
+                ));
+        }
+        welcome
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ws_base() {
+        assert_eq!(
+            ws_base("https://pair.example.com/"),
+            Ok("wss://pair.example.com".to_owned())
+        );
+        assert_eq!(
+            ws_base("http://localhost:8000/pair"),
+            Ok("ws://localhost:8000/pair".to_owned())
+        );
+        assert_eq!(
+            ws_base("WSS://pair.example.com"),
+            Ok("wss://pair.example.com".to_owned())
+        );
+        assert!(ws_base("pair.example.com").is_err());
+        assert!(ws_base("ftp://pair.example.com").is_err());
+        assert!(ws_base("https://pair.example.com/?a=b").is_err());
+    }
+
+    #[test]
+    fn test_welcome() {
+        let welcome = Links::default().welcome("abc", "tok");
+        assert_eq!(
+            welcome,
+            json!({"link": "/v1/ws/abc", "channelid": "abc", "token": "tok"})
+        );
+
+        let links = Links::new(&Settings {
+            public_url: "https://pair.example.com".to_owned(),
+            deep_link_template: "https://accounts.example.com/pair#channel={channelid}".to_owned(),
+            ..Default::default()
+        });
+        let welcome = links.welcome("abc", "tok");
+        assert_eq!(welcome["url"], "wss://pair.example.com/v1/ws/abc");
+        assert_eq!(
+            welcome["deep_link"],
+            "https://accounts.example.com/pair#channel=abc"
+        );
+        let links = Links::new(&Settings {
+            public_url: "https://pair.example.com".to_owned(),
+            deep_link_template: "https://accounts.example.com/pair?ws={url}".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(
+            links.welcome("abc", "tok")["deep_link"],
+            "https://accounts.example.com/pair?ws=wss%3A%2F%2Fpair.example.com%2Fv1%2Fws%2Fabc"
+        );
+
+        let public_url = "https://pair.example.com";
+        assert!(check_template("https://example.com/pair", public_url).is_err());
+        assert!(check_template("https://example.com/pair?ws={url}", public_url).is_ok());
+        assert!(check_template("https://example.com/pair?ws={url}", "").is_err());
+        assert!(check_template("https://example.com/pair#{channelid}", "").is_ok());
+    }
+}
@@ -0,0 +1,174 @@
+//! Listen endpoints.
+//!
+//! `listen` and `admin_listen` are comma delimited lists of endpoints. An
+//! endpoint is either a TCP address, `host:port` (`[::1]:8000` for an explicit
+//! IPv6 address), or a Unix domain socket, `unix:/path/to/socket`.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::settings::Settings;
+
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Endpoint {
+    /// `host:port`, resolved when bound
+    Tcp(String),
+    /// Path to a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(format!("Missing socket path: {:?}", s));
+            }
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        // The port follows the last colon; anything before it is the host,
+        // which must be bracketed if it's an IPv6 address.
+        let (host, port) = match s.rfind(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => return Err(format!("Missing port: {:?}", s)),
+        };
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(format!("Invalid listen address: {:?}", s));
+        }
+        if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+            return Err(format!("IPv6 addresses must be bracketed: {:?}", s));
+        }
+        Ok(Endpoint::Tcp(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Parse a comma delimited list of endpoints.
+pub fn parse_endpoints(list: &str) -> Result<Vec<Endpoint>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Parse the octal `unix_socket_mode`. Blank leaves the mode to the umask.
+pub fn parse_mode(mode: &str) -> Result<Option<u32>, String> {
+    let mode = mode.trim();
+    if mode.is_empty() {
+        return Ok(None);
+    }
+    match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+        Ok(bits) if bits <= 0o777 => Ok(Some(bits)),
+        _ => Err(format!("Invalid socket mode: {:?}", mode)),
+    }
+}
+
+/// The endpoints serving the websocket routes, and the ones serving the
+/// admin and health routes. If there are no separate admin endpoints, the
+/// admin routes are served alongside the websocket routes.
+pub fn endpoints(settings: &Settings) -> Result<(Vec<Endpoint>, Vec<Endpoint>), String> {
+    let mut public = parse_endpoints(&settings.listen)?;
+    if public.is_empty() {
+        public.push(Endpoint::Tcp(format!(
+            "{}:{}",
+            settings.hostname, settings.port
+        )));
+    }
+    Ok((public, parse_endpoints(&settings.admin_listen)?))
+}
+
+/// Bind a Unix domain socket, replacing a stale socket left behind by a
+/// previous run.
+pub fn unix_listener(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoints() {
+        assert_eq!(
+            parse_endpoints("0.0.0.0:8000, [::]:8000,localhost:80, unix:/run/pair.sock,"),
+            Ok(vec![
+                Endpoint::Tcp("0.0.0.0:8000".to_owned()),
+                Endpoint::Tcp("[::]:8000".to_owned()),
+                Endpoint::Tcp("localhost:80".to_owned()),
+                Endpoint::Unix(PathBuf::from("/run/pair.sock")),
+            ])
+        );
+        assert!(parse_endpoints("localhost").is_err());
+        assert!(parse_endpoints("::1:8000").is_err());
+        assert!(parse_endpoints("0.0.0.0:http").is_err());
+        assert!(parse_endpoints("unix:").is_err());
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let (public, admin) = endpoints(&Settings::default()).unwrap();
+        assert_eq!(public, vec![Endpoint::Tcp("0.0.0.0:8000".to_owned())]);
+        assert!(admin.is_empty());
+        let settings = Settings {
+            listen: "unix:/run/pair.sock".to_owned(),
+            admin_listen: "127.0.0.1:9000".to_owned(),
+            ..Default::default()
+        };
+        let (public, admin) = endpoints(&settings).unwrap();
+        assert_eq!(
+            public,
+            vec![Endpoint::Unix(PathBuf::from("/run/pair.sock"))]
+        );
+        assert_eq!(admin, vec![Endpoint::Tcp("127.0.0.1:9000".to_owned())]);
+    }
+
+    #[test]
+    fn test_unix_listener() {
+        assert_eq!(parse_mode(""), Ok(None));
+        assert_eq!(parse_mode("660"), Ok(Some(0o660)));
+        assert!(parse_mode("999").is_err());
+
+        let path = std::env::temp_dir().join(format!("listen-{}.sock", std::process::id()));
+        let listener = unix_listener(&path, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        // A stale socket is replaced.
+        assert!(unix_listener(&path, None).is_ok());
+        fs::remove_file(&path).unwrap();
+        // Anything else is left alone.
+        fs::write(&path, "data").unwrap();
+        assert!(unix_listener(&path, None).is_err());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,51 +1,124 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
+use actix_rt::signal::unix::{signal, SignalKind};
+use cadence::Counted;
+use docopt::{ArgvMap, Docopt};
 use futures::future::Future;
 use serde_json::Value;
-use slog::{debug, error, warn};
+use slog::{debug, error, info, warn};
 
 use actix::{Actor, Addr};
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{http, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 
+mod abuse;
+mod asn;
 #[macro_use]
 mod channelid;
 mod error;
+mod geo;
+mod geoheaders;
+mod ipfilter;
+mod language;
+mod links;
+mod listen;
 mod logging;
 mod meta;
 mod metrics;
+mod origin;
+mod pow;
+mod privacy;
+mod ratelimit;
 mod server;
 mod session;
 mod settings;
+mod tls;
+mod useragent;
 
 /* This code is modeled after the Actix example Websocket Chat Server.
    Which might explain random uses of "chat" appearing in portions of the code.
 */
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to check whether draining channels have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for sessions to send close frames before stopping
+const DRAIN_FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// Header carrying a proof-of-work solution
+const POW_HEADER: &str = "X-Channel-PoW";
 
 /// Entry point for our route
 async fn channel_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::ChannelServer>>,
+    ip_filter: web::Data<ipfilter::IpFilter>,
+    abuse: web::Data<abuse::AbuseLedger>,
+    pow: web::Data<pow::ProofOfWork>,
+    drain: web::Data<server::DrainState>,
 ) -> Result<HttpResponse, Error> {
-    let raw_state = req.app_data::<web::Data<session::WsChannelSessionState>>();
+    let raw_state = req.app_data::<web::Data<RwLock<session::WsChannelSessionState>>>();
     let state = match raw_state {
-        Some(state) => state,
+        Some(state) => state.read().unwrap_or_else(|e| e.into_inner()),
         None => {
             return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
         }
     };
+    let origin = req
+        .headers()
+        .get(http::header::ORIGIN)
+        .map(|o| o.to_str().unwrap_or_default());
+    if !state.origin_policy.is_allowed(origin) {
+        warn!(
+            state.log.log,
+            "Refusing connection from disallowed origin";
+            "origin" => origin,
+        );
+        state
+            .metrics
+            .incr(if origin.is_none() {
+                "conn.origin.missing"
+            } else {
+                "conn.origin.denied"
+            })
+            .ok();
+        return Ok(HttpResponse::Forbidden().finish());
+    }
     let meta = meta::SenderData::new(&req, &state);
+    let remote_ip = state.privacy.disclose_remote(&meta.remote);
     let mut path: Vec<&str> = req.path().split('/').collect();
     let log = logging::MozLogger::default();
     let metrics = state.metrics.clone();
+    let addr = meta.remote.as_ref().and_then(|r| r.parse::<IpAddr>().ok());
+    if !ip_filter.admits(addr.as_ref()) {
+        warn!(
+            state.log.log,
+            "Refusing connection from filtered address";
+            "remote_ip" => &remote_ip,
+        );
+        metrics
+            .incr(if addr.is_none() {
+                "conn.ip.unknown"
+            } else {
+                "conn.ip.denied"
+            })
+            .ok();
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    if let Some(addr) = addr {
+        if abuse.is_banned(&addr) {
+            info!(
+                state.log.log,
+                "Refusing connection from banned address";
+                "remote_ip" => &remote_ip,
+            );
+            metrics.incr("conn.banned").ok();
+            return Ok(HttpResponse::Forbidden().finish());
+        }
+    }
     let mut initial_connection: bool = true;
     let channel = match path.pop() {
         Some(id) => {
@@ -66,17 +139,95 @@ async fn channel_route(
         }
         None => channelid::ChannelID::default(),
     };
+    // A draining server lets existing channels finish, but takes no new ones.
+    if initial_connection && drain.is_draining() {
+        info!(
+            state.log.log,
+            "Refusing new channel while draining";
+            "remote_ip" => &remote_ip,
+        );
+        metrics.incr("conn.draining").ok();
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+    let mut query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    // Creating a channel may require solving a proof-of-work challenge. If
+    // there's no valid solution, hand back a challenge instead.
+    if initial_connection && pow.difficulty() > 0 {
+        let solution = req
+            .headers()
+            .get(POW_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(ToOwned::to_owned)
+            .or_else(|| query.remove("pow"));
+        let solved = match solution.map(|solution| pow.verify(&solution)) {
+            Some(Ok(())) => true,
+            Some(Err(err)) => {
+                info!(
+                    state.log.log,
+                    "Rejected proof of work: {}", err;
+                    "remote_ip" => &remote_ip,
+                );
+                metrics.incr("pow.invalid").ok();
+                abuse.record(&meta.remote, abuse::Infraction::InvalidPow);
+                false
+            }
+            None => false,
+        };
+        if !solved {
+            return match pow.issue() {
+                Ok(challenge) => {
+                    metrics.incr("pow.issued").ok();
+                    Ok(
+                        HttpResponse::build(http::StatusCode::PRECONDITION_REQUIRED)
+                            .json(challenge),
+                    )
+                }
+                Err(err) => {
+                    error!(state.log.log, "Could not issue challenge: {}", err);
+                    Ok(HttpResponse::InternalServerError().finish())
+                }
+            };
+        }
+        metrics.incr("pow.solved").ok();
+    }
+    // Principals rejoining a channel present the token they were issued.
+    let token = query.remove("token");
+    // The channel's creator may ask for less to be disclosed to its peers.
+    let restrictions = if initial_connection {
+        match privacy::Restrictions::from_query(&query) {
+            Ok(restrictions) => restrictions,
+            Err(err) => {
+                info!(
+                    state.log.log,
+                    "Refusing channel with bad disclosure restrictions: {}", err;
+                    "remote_ip" => &remote_ip,
+                );
+                return Ok(HttpResponse::BadRequest().body(err));
+            }
+        }
+    } else {
+        privacy::Restrictions::default()
+    };
     ws::start(
         session::WsChannelSession {
             id: 0,
             hb: Instant::now(),
-            expiry: Duration::from_secs(state.settings.conn_lifespan),
+            heartbeat: Duration::from_secs(state.settings.heartbeat),
+            client_timeout: Duration::from_secs(state.settings.client_timeout),
             channel,
             addr: srv.get_ref().clone(),
             initial_connection,
+            token,
+            restrictions,
             meta,
+            remote_ip,
             log,
             metrics,
+            limiter: ratelimit::RateLimiter::new(&state.settings),
+            rate_limit_action: state.rate_limit_action,
+            abuse: abuse.get_ref().clone(),
         },
         &req,
         stream,
@@ -96,9 +247,21 @@ pub fn heartbeat(_req: HttpRequest) -> impl Future<Output = Result<HttpResponse,
         .json(checklist)
 }
 
-fn lbheartbeat(_req: HttpRequest) -> impl Future<Output = Result<HttpResponse, Error>> {
-    // load balance heartbeat. Doesn't matter what's returned, aside from a 200
+fn lbheartbeat(
+    _req: HttpRequest,
+    drain: web::Data<server::DrainState>,
+) -> impl Future<Output = Result<HttpResponse, Error>> {
+    // load balance heartbeat. Doesn't matter what's returned, aside from a 200,
+    // unless we're draining, when the load balancer should stop sending us
+    // traffic.
+    if drain.is_draining() {
+        return HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .json(serde_json::json!({"status": "draining"}));
+    }
     HttpResponse::Ok()
+        .content_type("application/json")
+        .json(serde_json::json!({"status": "ok"}))
 }
 
 fn show_version(_req: HttpRequest) -> impl Future<Output = Result<HttpResponse, Error>> {
@@ -110,11 +273,124 @@ fn show_version(_req: HttpRequest) -> impl Future<Output = Result<HttpResponse,
 
 pub struct Server;
 
+/// The admin and health check routes.
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/__heartbeat__").route(web::get().to(heartbeat)))
+        .service(web::resource("/__lbheartbeat__").route(web::get().to(lbheartbeat)))
+        .service(web::resource("/__version__").route(web::get().to(show_version)));
+}
+
+/// Bind an `HttpServer` to each endpoint. TCP endpoints serve TLS if a
+/// certificate is configured.
+macro_rules! bind_endpoints {
+    ($http:expr, $endpoints:expr, $tls_cert:expr, $socket_mode:expr) => {{
+        let mut http = $http;
+        for endpoint in $endpoints {
+            http = match endpoint {
+                listen::Endpoint::Tcp(addr) => match $tls_cert {
+                    Some(ref cert) => {
+                        let acceptor = cert.acceptor().map_err(|err| {
+                            std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())
+                        })?;
+                        http.bind_openssl(addr, acceptor)?
+                    }
+                    None => http.bind(addr)?,
+                },
+                listen::Endpoint::Unix(path) => {
+                    http.listen_uds(listen::unix_listener(path, $socket_mode)?)?
+                }
+            };
+        }
+        http
+    }};
+}
+
+/// Drain the server before stopping: refuse new channels, give the existing
+/// ones up to `grace` to finish, then close whatever is left with a "going
+/// away" close code.
+async fn drain_and_stop(
+    grace: Duration,
+    drain: &server::DrainState,
+    server: &Addr<server::ChannelServer>,
+    servers: &[actix_web::dev::Server],
+    log: &logging::MozLogger,
+) {
+    drain.start();
+    info!(log.log, "Draining"; "grace_secs" => grace.as_secs());
+    let started = Instant::now();
+    while started.elapsed() < grace {
+        match server.send(server::ListChannels).await {
+            Ok(channels) if !channels.is_empty() => {
+                actix_rt::time::delay_for(DRAIN_POLL_INTERVAL).await
+            }
+            _ => break,
+        }
+    }
+    server.do_send(server::CloseAll(server::DisconnectReason::ServerShutdown));
+    // Give the sessions a moment to send their close frames.
+    actix_rt::time::delay_for(DRAIN_FLUSH_DELAY).await;
+    info!(log.log, "Drained, stopping");
+    for http in servers {
+        http.stop(true).await;
+    }
+    actix_rt::System::current().stop();
+}
+
+/// Re-read the settings and hand the ones that may change at runtime to the
+/// `ChannelServer`, which passes them on to the workers.
+fn reload_settings(
+    args: &ArgvMap,
+    current: &RwLock<settings::Settings>,
+    server: &Addr<server::ChannelServer>,
+    log: &logging::MozLogger,
+) {
+    let reloaded = match settings::Settings::new(args) {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!(
+                log.log,
+                "Could not reload settings, keeping the current ones: {:?}", err
+            );
+            return;
+        }
+    };
+    if let Err(problems) = reloaded.validate() {
+        for problem in &problems {
+            error!(log.log, "Invalid setting {}", problem);
+        }
+        error!(
+            log.log,
+            "Could not reload settings, keeping the current ones"
+        );
+        return;
+    }
+    let mut current = current.write().unwrap_or_else(|e| e.into_inner());
+    let (merged, refused) = current.merge_reload(reloaded);
+    for name in refused {
+        error!(
+            log.log,
+            "Setting {} can't be changed without a restart, ignoring the new value", name
+        );
+    }
+    *current = merged.clone();
+    server.do_send(server::UpdateSettings(merged));
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let raw_settings = settings::Settings::new();
+    let args = Docopt::new(settings::USAGE)
+        .and_then(|d| {
+            d.version(Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )))
+            .parse()
+        })
+        .unwrap_or_else(|e| e.exit());
+    let raw_settings = settings::Settings::new(&args);
     let settings = match raw_settings {
         Ok(settings) => settings,
         Err(e) => {
@@ -124,42 +400,185 @@ async fn main() -> std::io::Result<()> {
             ))
         }
     };
-    let addr = format!("{}:{}", settings.hostname, settings.port);
+    if args.get_bool("--check-config") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&settings.redacted()).unwrap_or_default()
+        );
+        return match settings.validate() {
+            Ok(()) => {
+                println!("Configuration OK");
+                Ok(())
+            }
+            Err(problems) => {
+                for problem in &problems {
+                    eprintln!("Invalid setting {}", problem);
+                }
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} invalid setting(s)", problems.len()),
+                ))
+            }
+        };
+    }
     let log = if settings.human_logs {
         logging::MozLogger::new_human()
     } else {
         logging::MozLogger::new_json()
     };
-
-    let server = server::ChannelServer::new(&settings, &log).start();
-
-    if !Path::new(&settings.mmdb_loc).exists() {
-        error!(
-            &log.log,
-            "Cannot find geoip database: {}", settings.mmdb_loc
-        );
+    if let Err(problems) = settings.validate() {
+        for problem in &problems {
+            error!(&log.log, "Invalid setting {}", problem);
+        }
         return Err(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "missing geoip database".to_owned(),
+            std::io::ErrorKind::InvalidInput,
+            format!("{} invalid setting(s)", problems.len()),
         ));
-    };
+    }
+
+    let (endpoints, admin_endpoints) = listen::endpoints(&settings)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let socket_mode = listen::parse_mode(&settings.unix_socket_mode)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let channel_server = server::ChannelServer::new(&settings, &log);
+    let abuse = channel_server.abuse.clone();
+    let pow = channel_server.pow.clone();
+    let privacy = channel_server.privacy.clone();
+    // One copy of the GeoIP database, shared by every worker.
+    let geo: Arc<dyn geo::GeoProvider> =
+        geo::from_settings(&settings, &log, &channel_server.metrics)
+            .map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Could not set up geolocation: {}", err),
+                )
+            })?
+            .into();
+    let asn = asn::from_settings(&settings, &log, &channel_server.metrics).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Could not open the ASN database: {}", err),
+        )
+    })?;
+    let server = channel_server.start();
+    let ip_filter = ipfilter::IpFilter::new(&settings, &log);
+    ip_filter.watch(Duration::from_secs(settings.ip_filter_reload));
+
+    let tls_cert = tls::TlsCertificate::new(&settings, &log).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Could not load TLS certificate: {}", err),
+        )
+    })?;
+    if let Some(ref cert) = tls_cert {
+        info!(log.log, "Serving TLS"; "cert" => &settings.tls_cert);
+        cert.watch(Duration::from_secs(settings.tls_reload));
+    }
+
+    // Reload the settings, and the TLS certificate, on SIGHUP.
+    let current = Arc::new(RwLock::new(settings));
+    {
+        let current = current.clone();
+        let server = server.clone();
+        let tls_cert = tls_cert.clone();
+        let log = log.clone();
+        let mut hangups = signal(SignalKind::hangup())?;
+        actix_rt::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!(log.log, "SIGHUP received, reloading settings");
+                reload_settings(&args, &current, &server, &log);
+                if let Some(ref cert) = tls_cert {
+                    cert.reload();
+                }
+            }
+        });
+    }
+
     // Create Http server with websocket support
-    debug!(&log.log, "Starting server: {:?}", &addr);
-    HttpServer::new(move || {
-        let state = session::WsChannelSessionState::new(&settings, &log);
-        App::new()
-            .data(server.clone())
-            .data(state)
-            .service(web::resource("/").to(|| HttpResponse::NotFound().finish()))
-            // websocket
-            .service(web::resource("/v1/ws/{channel}").to(channel_route))
-            .service(web::resource("/v1/ws/").route(web::get().to(channel_route)))
-            // static resources
-            .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat)))
-            .service(web::resource("/__lbheartbeat__").route(web::get().to(lbheartbeat)))
-            .service(web::resource("/__version__").route(web::get().to(show_version)))
-    })
-    .bind(addr)?
-    .run()
-    .await
+    debug!(&log.log, "Starting server: {:?}", &endpoints);
+    let drain = server::DrainState::default();
+    // Serve the admin routes alongside the websocket routes, unless they have
+    // their own endpoints.
+    let inline_admin = admin_endpoints.is_empty();
+    let http = {
+        let current = current.clone();
+        let server = server.clone();
+        let drain = drain.clone();
+        let log = log.clone();
+        let http = HttpServer::new(move || {
+            let settings = current.read().unwrap_or_else(|e| e.into_inner()).clone();
+            let state = web::Data::new(RwLock::new(session::WsChannelSessionState::new(
+                &settings,
+                &log,
+                geo.clone(),
+                asn.clone(),
+                privacy.reconfigure(&settings),
+            )));
+            // Hear about settings reloads.
+            let updater = session::StateUpdater {
+                state: state.clone(),
+            }
+            .start();
+            server.do_send(server::RegisterWorker(updater.recipient()));
+            App::new()
+                .data(server.clone())
+                .data(ip_filter.clone())
+                .data(abuse.clone())
+                .data(pow.clone())
+                .data(drain.clone())
+                .app_data(state)
+                .service(web::resource("/").to(|| HttpResponse::NotFound().finish()))
+                // websocket
+                .service(web::resource("/v1/ws/{channel}").to(channel_route))
+                .service(web::resource("/v1/ws/").route(web::get().to(channel_route)))
+                // static resources
+                .configure(|cfg| {
+                    if inline_admin {
+                        admin_routes(cfg)
+                    }
+                })
+        })
+        // Termination signals are handled below, so channels can be drained.
+        .disable_signals();
+        bind_endpoints!(http, &endpoints, tls_cert, socket_mode).run()
+    };
+    let mut servers = vec![http.clone()];
+    if !inline_admin {
+        debug!(&log.log, "Starting admin server: {:?}", &admin_endpoints);
+        let drain = drain.clone();
+        let admin = HttpServer::new(move || App::new().data(drain.clone()).configure(admin_routes))
+            .workers(1)
+            .disable_signals();
+        servers.push(bind_endpoints!(admin, &admin_endpoints, tls_cert, socket_mode).run());
+    }
+
+    // Drain on SIGTERM. SIGINT and SIGQUIT skip the grace period, and cut
+    // short a drain that's already underway.
+    for (kind, graceful) in &[
+        (SignalKind::terminate(), true),
+        (SignalKind::interrupt(), false),
+        (SignalKind::quit(), false),
+    ] {
+        let mut signals = signal(*kind)?;
+        let graceful = *graceful;
+        let current = current.clone();
+        let drain_state = drain.clone();
+        let server = server.clone();
+        let servers = servers.clone();
+        let log = log.clone();
+        actix_rt::spawn(async move {
+            if signals.recv().await.is_none() {
+                return;
+            }
+            let grace = if graceful {
+                let settings = current.read().unwrap_or_else(|e| e.into_inner());
+                Duration::from_secs(settings.shutdown_grace)
+            } else {
+                Duration::from_secs(0)
+            };
+            drain_and_stop(grace, &drain_state, &server, &servers, &log).await;
+        });
+    }
+    http.await
 }
@@ -1,5 +1,5 @@
-use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
 
 use actix_web::{
     dev::Payload,
@@ -8,13 +8,15 @@ use actix_web::{
 };
 use futures::future::{ok, Ready};
 use ipnet::IpNet;
-use maxminddb::{self, geoip2::City, MaxMindDBError};
 use serde::{self, Serialize};
-use slog::{debug, error, info, warn};
+use slog::{error, info, warn};
 
 use crate::error::{HandlerError, HandlerErrorKind};
+use crate::geo::{Client, Location, Name};
+use crate::language::Languages;
 use crate::logging;
 use crate::session::WsChannelSessionState;
+use crate::useragent;
 
 // Sender meta data, drawn from the HTTP Headers of the connection counterpart.
 #[derive(Serialize, Debug, Default, Clone)]
@@ -22,6 +24,14 @@ pub struct SenderData {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ua: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub remote: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub city: Option<String>,
@@ -29,88 +39,35 @@ pub struct SenderData {
     pub region: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub country: Option<String>,
-}
-
-// Parse the Accept-Language header to get the list of preferred languages.
-// We default to "en" because of well-established Anglo-biases.
-fn preferred_languages(alheader: String, default: &str) -> Vec<String> {
-    let default_lang = String::from(default);
-    let mut lang_tree: BTreeMap<String, String> = BTreeMap::new();
-    let mut i = 0;
-    alheader.split(',').for_each(|l| {
-        if l != "-" {
-            if l.contains(';') {
-                let weight: Vec<&str> = l.split(';').collect();
-                let lang = weight[0].to_ascii_lowercase();
-                let pref = weight[1].to_ascii_lowercase();
-                lang_tree.insert(String::from(pref.trim()), String::from(lang.trim()));
-            } else {
-                lang_tree.insert(format!("q=1.{:02}", i), l.to_ascii_lowercase());
-                i += 1;
-            }
-        }
-    });
-    let mut langs: Vec<String> = lang_tree
-        .values()
-        .map(std::borrow::ToOwned::to_owned)
-        .collect();
-    langs.reverse();
-    langs.push(default_lang);
-    langs
-}
-
-// Return the element that most closely matches the preferred language.
-// This rounds up from the dialect if possible.
-fn get_preferred_language_element(
-    langs: &[String],
-    elements: BTreeMap<String, String>,
-) -> Option<String> {
-    for lang in langs {
-        // It's a wildcard, so just return the first possible choice.
-        if lang == "*" || lang == "-" {
-            return elements.values().next().map(std::borrow::ToOwned::to_owned);
-        }
-        if elements.contains_key(lang) {
-            if let Some(element) = elements.get(lang.as_str()) {
-                return Some(element.to_string());
-            }
-        }
-        if lang.contains('-') {
-            let (lang, _) = lang.split_at(2);
-            if elements.contains_key(lang) {
-                if let Some(element) = elements.get(lang) {
-                    return Some(element.to_string());
-                }
-            }
-        }
-    }
-    None
-}
-
-#[allow(unreachable_patterns)]
-fn handle_city_err(log: &logging::MozLogger, err: &MaxMindDBError) {
-    match err {
-        maxminddb::MaxMindDBError::InvalidDatabaseError(s) => {
-            error!(log.log, "Invalid GeoIP database! {:?}", s);
-            ::std::process::exit(-1);
-        }
-        maxminddb::MaxMindDBError::IoError(s) => error!(log.log, "Could not read database {:?}", s),
-        maxminddb::MaxMindDBError::MapError(s) => warn!(log.log, "Mapping error: {:?}", s),
-        maxminddb::MaxMindDBError::DecodingError(s) => {
-            warn!(log.log, "Could not decode mapping result: {:?}", s)
-        }
-        maxminddb::MaxMindDBError::AddressNotFoundError(s) => {
-            debug!(log.log, "Could not find address for IP: {:?}", s)
-        }
-        // include to future proof against cross compile dependency errors
-        _ => error!(log.log, "Unknown GeoIP error encountered: {:?}", err),
-    };
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    /// In kilometers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy_radius: Option<u16>,
+    /// Autonomous system number of the sender's network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Organization the sender's network is registered to, e.g. an ISP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_org: Option<String>,
+    /// Every name known for the location, for peers with other languages
+    #[serde(skip)]
+    pub location: Location,
+    /// The sender's own language preferences
+    #[serde(skip)]
+    pub langs: Languages,
 }
 
 fn get_ua(
     headers: &http::HeaderMap,
     log: &logging::MozLogger,
-    meta: &SenderData,
+    remote_ip: &Option<String>,
 ) -> Option<String> {
     if let Some(ua) = headers
         .get(http::header::USER_AGENT)
@@ -119,7 +76,7 @@ fn get_ua(
                 warn!(
                     log.log,
                     "Bad UA string: {:?}", x;
-                    "remote_ip" => &meta.remote
+                    "remote_ip" => remote_ip
                 );
                 // We have to return Some value here.
                 "".to_owned()
@@ -141,10 +98,24 @@ fn is_trusted_proxy(proxy_list: &[IpNet], host: &IpAddr) -> bool {
     proxy_list.iter().any(|range| range.contains(host))
 }
 
+/// Is the connection from a trusted proxy? Connections on a Unix domain
+/// socket have no peer address, and are only trusted with `trust_unix_socket`.
+fn peer_is_trusted(
+    peer: &Option<SocketAddr>,
+    proxy_list: &[IpNet],
+    trust_unix_socket: bool,
+) -> bool {
+    match peer {
+        None => trust_unix_socket,
+        Some(peer) => is_trusted_proxy(proxy_list, &peer.ip()),
+    }
+}
+
 fn get_remote(
     peer: &Option<SocketAddr>,
     headers: &http::HeaderMap,
     proxy_list: &[IpNet],
+    trust_unix_socket: bool,
     log: &logging::MozLogger,
 ) -> Result<String, HandlerError> {
     // Actix determines the connection_info.remote() from the first entry in the
@@ -154,18 +125,19 @@ fn get_remote(
     // our way back up the proxy chain until we find the first unexpected address.
     // This may be an intermediary proxy, or it may be the original requesting system.
     //
-    let peer_ip = match peer {
-        None => {
-            return Err(
-                HandlerErrorKind::BadRemoteAddrError("Peer is unspecified".to_owned()).into(),
-            );
+    // if the peer is not a known proxy, ignore the X-Forwarded-For headers. A peer without an
+    // address is on a Unix domain socket, and is only a known proxy if the operator says so.
+    match peer {
+        Some(peer) if !is_trusted_proxy(proxy_list, &peer.ip()) => {
+            return Ok(peer.ip().to_string());
         }
-        Some(v) => v,
-    }
-    .ip();
-    // if the peer is not a known proxy, ignore the X-Forwarded-For headers
-    if !is_trusted_proxy(proxy_list, &peer_ip) {
-        return Ok(peer_ip.to_string());
+        None if !trust_unix_socket => {
+            return Err(HandlerErrorKind::BadRemoteAddrError(
+                "Unix socket peer not trusted".to_owned(),
+            )
+            .into());
+        }
+        _ => {}
     }
 
     // The peer is a known proxy, so take rightmost X-Forwarded-For that is not a trusted proxy.
@@ -173,7 +145,6 @@ fn get_remote(
         Some(header) => {
             match header.to_str() {
                 Ok(hstr) => {
-                    info!(log.log, "Remote IP List: {:?}", hstr);
                     // successive proxies are appeneded to this header.
                     let mut host_list: Vec<&str> = hstr.split(',').collect();
                     host_list.reverse();
@@ -187,7 +158,6 @@ fn get_remote(
                             Err(err) => {
                                 info!(log.log,
                                     "Bad IP Specified";
-                                    "remote_ip" => host_str.trim(),
                                     "err" => format!("{:?}", err),
                                 );
                                 return Err(HandlerErrorKind::BadRemoteAddrError(
@@ -216,114 +186,18 @@ fn get_remote(
     }
 }
 
-fn get_location(
-    sender: &mut SenderData,
-    langs: &[String],
-    log: &logging::MozLogger,
-    iploc: &maxminddb::Reader<Vec<u8>>,
-    default_lang: &str,
-) {
-    if sender.remote.is_some() {
-        debug!(
-            log.log,
-            "Looking up IP";
-            "remote_ip" => &sender.remote
-        );
-        // Strip the port from the remote (if present)
-        let remote = sender
-            .remote
-            .clone()
-            .map(|mut r| {
-                let end = r.find(':').unwrap_or_else(|| r.len());
-                r.drain(..end).collect()
-            })
-            .unwrap_or_else(|| default_lang.to_owned());
-        if let Ok(loc) = remote.parse() {
-            if let Ok(city) = iploc.lookup::<City>(loc).map_err(|err| {
-                handle_city_err(log, &err);
-                err
-            }) {
-                /*
-                    The structure of the returned maxminddb record is:
-                    City:maxminddb::geoip::model::City {
-                        city: Some(City{
-                            geoname_id: Some(#),
-                            names: Some({"lang": "name", ...})
-                            }),
-                        continent: Some(Continent{
-                            geoname_id: Some(#),
-                            names: Some({...})
-                            }),
-                        country: Some(Country{
-                            geoname_id: Some(#),
-                            names: Some({...})
-                            }),
-                        location: Some(Location{
-                            latitude: Some(#.#),
-                            longitude: Some(#.#),
-                            metro_code: Some(#),
-                            time_zone: Some(".."),
-                            }),
-                        postal: Some(Postal {
-                            code: Some("..")
-                            }),
-                        registered_country: Some(Country {
-                            geoname_id: Some(#),
-                            iso_code: Some(".."),
-                            names: Some({"lang": "name", ...})
-                            }),
-                        represented_country: None,
-                        subdivisions: Some([Subdivision {
-                            geoname_id: Some(#),
-                            iso_code: Some(".."),
-                            names: Some({"lang": "name", ...})
-                            }]),
-                        traits: None }
-                    }
-                */
-                if let Some(names) = city
-                    .city
-                    .and_then(|c: maxminddb::geoip2::model::City| c.names)
-                {
-                    sender.city = get_preferred_language_element(&langs, names);
-                }
-                if let Some(names) = city
-                    .country
-                    .and_then(|c: maxminddb::geoip2::model::Country| c.names)
-                {
-                    sender.country = get_preferred_language_element(&langs, names);
-                }
-                // because consistency is overrated.
-                if let Some(subdivisions) = city.subdivisions {
-                    if let Some(subdivision) = subdivisions.get(0) {
-                        if let Some(names) = subdivision.clone().names {
-                            sender.region = get_preferred_language_element(&langs, names);
-                        }
-                    }
-                }
-            } else {
-                info!(
-                    log.log,
-                    "No location info for IP";
-                    "remote_ip" => &sender.remote,
-                    "lang" => format!("{:?}", &langs),
-                )
-            }
-        }
-    }
-}
-
 impl FromRequest for SenderData {
     type Error = Error;
     type Future = Ready<Result<Self, Self::Error>>;
     type Config = ();
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let data = match req.app_data::<web::Data<WsChannelSessionState>>() {
+        let data = match req.app_data::<web::Data<RwLock<WsChannelSessionState>>>() {
             Some(data) => data,
             None => panic!("Data not found"),
         };
-        ok(SenderData::new(req, data))
+        let data = data.read().unwrap_or_else(|e| e.into_inner());
+        ok(SenderData::new(req, &data))
     }
 }
 
@@ -337,82 +211,89 @@ impl SenderData {
             &req.peer_addr(),
             &req.headers(),
             &data.trusted_proxy_list,
+            data.settings.trust_unix_socket,
             &data.log,
         ) {
             Ok(addr) => Some(addr),
             Err(err) => {
-                error!(
-                    data.log.log,
-                    "{:?}", err;
-                    "remote_ip" => &sender.remote
-                );
+                error!(data.log.log, "{:?}", err);
                 None
             }
         };
+        let remote_ip = data.privacy.disclose_remote(&sender.remote);
         let langs = match headers.get(http::header::ACCEPT_LANGUAGE) {
-            None => preferred_languages(default_lang.clone(), default_lang),
+            None => Languages::parse("", default_lang),
             Some(l) => {
                 let lang = match l.to_str() {
                     Err(err) => {
                         warn!(
                             data.log.log,
                             "Bad Accept-Language string: {:?}", err;
-                            "remote_ip" => &sender.remote
+                            "remote_ip" => &remote_ip
                         );
-                        &data.settings.default_lang
+                        ""
                     }
                     Ok(ls) => ls,
                 };
-                preferred_languages(lang.to_owned(), default_lang)
+                Languages::parse(lang, default_lang)
             }
         };
         // parse user-header for platform info
-        sender.ua = get_ua(&headers, &data.log, &sender);
-        get_location(
-            &mut sender,
-            &langs,
-            &data.log,
-            &data.iploc,
-            &data.settings.default_lang,
-        );
-        // If there's no sender, try pulling the GCP header.
-        // NOTE: This is US/EN only, so localization should come later.
-        if sender.city.is_none() {
-            if let Some(ghead) = headers.get("X-Client-Geo-Location") {
-                if let Ok(loc_str) = ghead.to_str() {
-                    let bits = loc_str.split(',').collect::<Vec<&str>>();
-                    let mut bi = bits.iter();
-                    sender.region = bi.next().map(|s| (*s).to_owned());
-                    sender.city = bi.next().map(|s| (*s).to_owned());
-                }
+        if let Some(ua) = get_ua(&headers, &data.log, &remote_ip) {
+            let parsed = useragent::parse(&ua);
+            sender.browser = parsed.browser;
+            sender.browser_version = parsed.browser_version;
+            sender.os = parsed.os;
+            sender.device = parsed.device.map(|device| device.as_str().to_owned());
+            if data.settings.forward_raw_ua {
+                sender.ua = Some(ua);
+            }
+        }
+        let client = Client {
+            addr: sender
+                .remote
+                .as_ref()
+                .and_then(|r| r.parse::<IpAddr>().ok()),
+            via_trusted_proxy: peer_is_trusted(
+                &req.peer_addr(),
+                &data.trusted_proxy_list,
+                data.settings.trust_unix_socket,
+            ),
+            headers,
+        };
+        sender.langs = langs;
+        if let Some(location) = data.geo.locate(&client, &data.log) {
+            sender.set_location(location);
+        }
+        if let (Some(asn), Some(addr)) = (&data.asn, client.addr) {
+            if let Some(network) = asn.lookup(addr, &data.log) {
+                sender.asn = network.number;
+                sender.as_org = network.organization;
             }
         }
         sender
     }
-}
 
-/// Convert the Sender Metadata into a optional hash of data. Only include things that are set.
-/// This is used mostly by the logger.
-impl Into<Option<HashMap<String, String>>> for SenderData {
-    fn into(self) -> Option<HashMap<String, String>> {
-        let mut map: HashMap<String, String> = HashMap::new();
-        // Do not include UA string for PII reasons.
-        if let Some(val) = self.remote {
-            map.insert("remote_ip".to_owned(), val);
+    /// The sender as shown to a peer with the language preferences `langs`,
+    /// which need not be the sender's own.
+    pub fn localized(&self, langs: &Languages) -> Self {
+        let name = |name: &Option<Name>| name.as_ref().and_then(|name| name.localize(langs));
+        Self {
+            city: name(&self.location.city),
+            region: name(&self.location.region),
+            country: name(&self.location.country),
+            ..self.clone()
         }
-        if let Some(val) = self.city {
-            map.insert("remote_city".to_owned(), val);
-        }
-        if let Some(val) = self.region {
-            map.insert("remote_region".to_owned(), val);
-        }
-        if let Some(val) = self.country {
-            map.insert("remote_country".to_owned(), val);
-        }
-        if !map.is_empty() {
-            return Some(map);
-        }
-        None
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.country_code = location.country_code.clone();
+        self.region_code = location.region_code.clone();
+        self.continent = location.continent.clone();
+        self.time_zone = location.time_zone.clone();
+        self.accuracy_radius = location.accuracy_radius;
+        self.location = location;
+        *self = self.localized(&self.langs);
     }
 }
 
@@ -422,62 +303,14 @@ mod test {
     use actix_web;
     use std::collections::BTreeMap;
 
-    use actix_web::http;
+    use std::sync::Arc;
 
-    #[test]
-    fn test_preferred_language() {
-        let langs = preferred_languages("en-US,es;q=0.1,en;q=0.5,*;q=0.2".to_owned(), "en");
-        assert_eq!(
-            vec![
-                "en-us".to_owned(),
-                "en".to_owned(),
-                "*".to_owned(),
-                "es".to_owned(),
-                "en".to_owned(),
-            ],
-            langs
-        );
-    }
+    use actix_web::{http, test::TestRequest};
 
-    #[test]
-    fn test_bad_preferred_language() {
-        let langs = preferred_languages("-".to_owned(), "en");
-        assert_eq!(vec!["en".to_owned()], langs);
-    }
-
-    #[test]
-    fn test_get_preferred_language_element() {
-        let langs = vec![
-            "en-us".to_owned(),
-            "en".to_owned(),
-            "es".to_owned(),
-            "en".to_owned(),
-        ];
-        // Don't include the default "en" so we can test no matching languages.
-        let bad_lang = vec!["fu".to_owned()];
-        // Include the "*" so we can return any language.
-        let any_lang = vec!["fu".to_owned(), "*".to_owned(), "en".to_owned()];
-        let mut elements = BTreeMap::new();
-        elements.insert("de".to_owned(), "Kalifornien".to_owned());
-        elements.insert("en".to_owned(), "California".to_owned());
-        elements.insert("fr".to_owned(), "Californie".to_owned());
-        elements.insert("ja".to_owned(), "カリフォルニア州".to_owned());
-        assert_eq!(
-            Some("California".to_owned()),
-            get_preferred_language_element(&langs, elements.clone())
-        );
-        assert_eq!(
-            None,
-            get_preferred_language_element(&bad_lang, elements.clone())
-        );
-        // Return Dutch, since it's the first key listed.
-        assert!(get_preferred_language_element(&any_lang, elements.clone()).is_some());
-        let goof_lang = vec!["🙄💩".to_owned()];
-        assert_eq!(
-            None,
-            get_preferred_language_element(&goof_lang, elements.clone())
-        );
-    }
+    use crate::asn::{test::asn_mmdb, AsnProvider};
+    use crate::geo::{test::nop_metrics, GeoProvider};
+    use crate::privacy::DisclosurePolicy;
+    use crate::settings::Settings;
 
     #[test]
     fn test_ua() {
@@ -492,54 +325,179 @@ mod test {
         );
         assert_eq!(
             Some(good_header.to_owned()),
-            get_ua(&good_headers, &log, &meta)
+            get_ua(&good_headers, &log, &meta.remote)
         );
         let mut blank_headers = http::HeaderMap::new();
         blank_headers.insert(
             http::header::USER_AGENT,
             http::header::HeaderValue::from_static(blank_header),
         );
-        assert_eq!(None, get_ua(&blank_headers, &log, &meta));
+        assert_eq!(None, get_ua(&blank_headers, &log, &meta.remote));
         let empty_headers = http::HeaderMap::new();
-        assert_eq!(None, get_ua(&empty_headers, &log, &meta));
+        assert_eq!(None, get_ua(&empty_headers, &log, &meta.remote));
     }
 
-    #[test]
-    fn test_location_good() {
-        let test_ip = "63.245.208.195"; // Mozilla
-        let log = logging::MozLogger::new_human();
-        let langs = vec!["en".to_owned()];
-        let mut sender = SenderData::default();
-        sender.remote = Some(test_ip.to_owned());
-        // TODO: either mock maxminddb::Reader or pass it in as a wrapped impl
-        let iploc =
-            maxminddb::Reader::open_readfile("mmdb/latest/GeoLite2-City.mmdb").expect(&format!(
-                "Could not find mmdb file at {:?}/mmdb/latest/GeoLite2-City.mmdb",
-                std::env::current_dir().unwrap().as_path().to_string_lossy()
-            ));
-        get_location(&mut sender, &langs, &log, &iploc, "en");
-        assert_eq!(sender.city, Some("Sacramento".to_owned()));
-        assert_eq!(sender.region, Some("California".to_owned()));
-        assert_eq!(sender.country, Some("United States".to_owned()));
+    /// Locates addresses in 63.245.208.0/24.
+    struct FakeGeo;
+
+    impl GeoProvider for FakeGeo {
+        fn locate(&self, client: &Client, _log: &logging::MozLogger) -> Option<Location> {
+            if !"63.245.208.0/24"
+                .parse::<IpNet>()
+                .unwrap()
+                .contains(&client.addr?)
+            {
+                return None;
+            }
+            let mut cities = BTreeMap::new();
+            cities.insert("de".to_owned(), "Sankt Petersburg".to_owned());
+            cities.insert("en".to_owned(), "Saint Petersburg".to_owned());
+            Some(Location {
+                city: Some(Name::Localized(cities)),
+                region: Some("Florida".into()),
+                country_code: Some("US".to_owned()),
+                region_code: Some("US-FL".to_owned()),
+                time_zone: Some("America/New_York".to_owned()),
+                ..Default::default()
+            })
+        }
     }
 
     #[test]
-    fn test_location_bad() {
-        let test_ip = "192.168.1.1";
+    fn test_location() {
         let log = logging::MozLogger::new_human();
-        let langs = vec!["en".to_owned()];
-        let mut sender = SenderData::default();
-        sender.remote = Some(test_ip.to_owned());
-        // TODO: either mock maxminddb::Reader or pass it in as a wrapped impl
-        let iploc =
-            maxminddb::Reader::open_readfile("mmdb/latest/GeoLite2-City.mmdb").expect(&format!(
-                "Could not find mmdb file at {:?}/mmdb/latest/GeoLite2-City.mmdb",
-                std::env::current_dir().unwrap().as_path().to_string_lossy()
-            ));
-        get_location(&mut sender, &langs, &log, &iploc, "en");
+        let settings = Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            Arc::new(FakeGeo),
+            None,
+            DisclosurePolicy::new(&settings),
+        );
+
+        let req = TestRequest::default()
+            .peer_addr("63.245.208.195:0".parse().unwrap())
+            .header(http::header::ACCEPT_LANGUAGE, "de-DE, en;q=0.5")
+            .to_http_request();
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.remote, Some("63.245.208.195".to_owned()));
+        assert_eq!(sender.city, Some("Sankt Petersburg".to_owned()));
+        assert_eq!(sender.region, Some("Florida".to_owned()));
+        assert_eq!(sender.country, None);
+        assert_eq!(sender.country_code, Some("US".to_owned()));
+        assert_eq!(sender.region_code, Some("US-FL".to_owned()));
+        assert_eq!(sender.time_zone, Some("America/New_York".to_owned()));
+        // A peer reading in another language gets the names in its own.
+        let shown = sender.localized(&Languages::parse("en-US, de;q=0.5", "en"));
+        assert_eq!(shown.city, Some("Saint Petersburg".to_owned()));
+        assert_eq!(shown.region, Some("Florida".to_owned()));
+        assert_eq!(shown.remote, sender.remote);
+        let shown = sender.localized(&Languages::parse("fr", "ja"));
+        assert_eq!(shown.city, None);
+
+        let req = TestRequest::default()
+            .peer_addr("1.2.3.4:0".parse().unwrap())
+            .to_http_request();
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.remote, Some("1.2.3.4".to_owned()));
         assert_eq!(sender.city, None);
         assert_eq!(sender.region, None);
-        assert_eq!(sender.country, None);
+    }
+
+    #[test]
+    fn test_parsed_ua() {
+        let log = logging::MozLogger::new_human();
+        let ua = "Mozilla/5.0 (Android 10; Mobile; rv:79.0) Gecko/79.0 Firefox/79.0";
+        let mut settings = Settings {
+            statsd_host: "".to_owned(),
+            forward_raw_ua: true,
+            ..Default::default()
+        };
+        let req = TestRequest::default()
+            .peer_addr("1.2.3.4:0".parse().unwrap())
+            .header(http::header::USER_AGENT, ua)
+            .to_http_request();
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            Arc::new(FakeGeo),
+            None,
+            DisclosurePolicy::new(&settings),
+        );
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.ua, Some(ua.to_owned()));
+        assert_eq!(sender.browser, Some("Firefox".to_owned()));
+        assert_eq!(sender.browser_version, Some("79".to_owned()));
+        assert_eq!(sender.os, Some("Android".to_owned()));
+        assert_eq!(sender.device, Some("phone".to_owned()));
+
+        // Only the parsed parts are sent by default.
+        settings.forward_raw_ua = Settings::default().forward_raw_ua;
+        assert!(!settings.forward_raw_ua);
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            Arc::new(FakeGeo),
+            None,
+            DisclosurePolicy::new(&settings),
+        );
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.ua, None);
+        assert_eq!(sender.browser, Some("Firefox".to_owned()));
+        let logged = DisclosurePolicy::new(&settings).log_fields(&sender);
+        assert_eq!(logged.unwrap()["remote_os"], "Android");
+
+        settings.withhold_fields = "os, remote".to_owned();
+        let logged = DisclosurePolicy::new(&settings).log_fields(&sender);
+        let logged = logged.unwrap();
+        assert!(!logged.contains_key("remote_os"));
+        assert!(!logged.contains_key("remote_ip"));
+        assert_eq!(logged["remote_browser"], "Firefox");
+    }
+
+    #[test]
+    fn test_asn() {
+        let log = logging::MozLogger::new_human();
+        let mut settings = Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("meta-asn-{}.mmdb", std::process::id()));
+        std::fs::write(&path, asn_mmdb("GeoLite2-ASN")).unwrap();
+        let asn = AsnProvider::open(&path.to_string_lossy(), &nop_metrics()).unwrap();
+        std::fs::remove_file(&path).ok();
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            Arc::new(FakeGeo),
+            Some(asn),
+            DisclosurePolicy::new(&settings),
+        );
+
+        let req = TestRequest::default()
+            .peer_addr("63.245.208.195:0".parse().unwrap())
+            .to_http_request();
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.asn, Some(53_319));
+        assert_eq!(sender.as_org, Some("MOZILLA-MDC1".to_owned()));
+        assert_eq!(sender.city, Some("Saint Petersburg".to_owned()));
+        let logged = DisclosurePolicy::new(&settings).log_fields(&sender);
+        assert_eq!(logged.unwrap()["remote_asn"], "53319");
+
+        settings.withhold_fields = "as_org".to_owned();
+        let shown = DisclosurePolicy::new(&settings).apply(&sender);
+        assert_eq!(shown.asn, Some(53_319));
+        assert_eq!(shown.as_org, None);
+
+        let req = TestRequest::default()
+            .peer_addr("1.2.3.4:0".parse().unwrap())
+            .to_http_request();
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.asn, None);
+        assert_eq!(sender.as_org, None);
     }
 
     #[test]
@@ -561,11 +519,17 @@ mod test {
         );
 
         // Proxy only, no XFF header
-        let remote = get_remote(&Some(proxy_server), &empty_headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &empty_headers,
+            &proxy_list,
+            false,
+            &log,
+        );
         assert!(remote.is_err());
 
         // Proxy only, bad XFF header
-        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, &log);
+        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, false, &log);
         assert!(remote.is_err());
 
         // Proxy only, crap XFF header
@@ -573,11 +537,11 @@ mod test {
             http::header::HeaderName::from_lowercase("x-forwarded-for".as_bytes()).unwrap(),
             "invalid".parse().unwrap(),
         );
-        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, &log);
+        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, false, &log);
         assert!(remote.is_err());
 
         // Peer only, no header
-        let remote = get_remote(&Some(true_remote), &empty_headers, &proxy_list, &log);
+        let remote = get_remote(&Some(true_remote), &empty_headers, &proxy_list, false, &log);
         assert_eq!(remote.unwrap(), "1.2.3.4".to_owned());
 
         headers.insert(
@@ -586,7 +550,7 @@ mod test {
         );
 
         // Peer proxy, fetch from XFF header
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, false, &log);
         assert_eq!(remote.unwrap(), "1.2.3.4".to_owned());
 
         // Peer proxy, ensure right most XFF client fetched
@@ -595,7 +559,7 @@ mod test {
             "1.2.3.4, 2.3.4.5".parse().unwrap(),
         );
 
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, false, &log);
         assert_eq!(remote.unwrap(), "2.3.4.5".to_owned());
 
         // Peer proxy, ensure right most non-proxy XFF client fetched
@@ -604,7 +568,51 @@ mod test {
             "1.2.3.4, 2.3.4.5, 192.168.0.10".parse().unwrap(),
         );
 
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, false, &log);
         assert_eq!(remote.unwrap(), "2.3.4.5".to_owned());
     }
+    #[test]
+    fn test_unix_socket_peer() {
+        let log = logging::MozLogger::new_human();
+        let mut settings = Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let state = |settings: &Settings| {
+            WsChannelSessionState::new(
+                settings,
+                &log,
+                Arc::new(FakeGeo),
+                None,
+                DisclosurePolicy::new(settings),
+            )
+        };
+        // A Unix domain socket peer has no address. Unless it's trusted, its
+        // headers aren't believed, so the client's address is unknown.
+        let req = TestRequest::default()
+            .header("x-forwarded-for", "63.245.208.195, 127.0.0.1")
+            .to_http_request();
+        assert_eq!(req.peer_addr(), None);
+        assert!(!Settings::default().trust_unix_socket);
+        let sender = SenderData::new(&req, &state(&settings));
+        assert_eq!(sender.remote, None);
+        assert_eq!(sender.city, None);
+        assert!(!peer_is_trusted(&req.peer_addr(), &[], false));
+
+        // A trusted sidecar proxy passes the client's address in its
+        // X-Forwarded-For.
+        settings.trust_unix_socket = true;
+        let state = state(&settings);
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.remote, Some("63.245.208.195".to_owned()));
+        assert_eq!(sender.city, Some("Saint Petersburg".to_owned()));
+        assert!(peer_is_trusted(
+            &req.peer_addr(),
+            &state.trusted_proxy_list,
+            true
+        ));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(SenderData::new(&req, &state).remote, None);
+    }
 }
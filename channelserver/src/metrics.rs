@@ -25,7 +25,9 @@ pub fn metrics_from_opts(
         } else {
             (settings.statsd_host.as_str(), "8529")
         };
-        let port = host.1.parse::<u16>().unwrap_or(8529);
+        let port = host.1.parse::<u16>().map_err(|_| {
+            c_error::HandlerErrorKind::MetricsError(format!("Bad statsd port {:?}", host.1))
+        })?;
         let udp_sink = BufferedUdpMetricSink::from((host.0, port), socket)?;
         let sink = QueuingMetricSink::from(udp_sink);
         info!(log.log,
@@ -0,0 +1,263 @@
+//! `Origin` header checks for websocket upgrades.
+//!
+//! Browsers always send `Origin` on websocket upgrades, so checking it keeps
+//! arbitrary web pages from opening channels on a user's behalf. Native
+//! clients usually send no `Origin` at all, and are handled by a separate
+//! policy.
+//!
+//! `allowed_origins` entries are either exact origins (`https://example.com`,
+//! `https://example.com:8443`) or wildcard subdomains (`https://*.example.com`).
+//! The scheme may be left off to match any scheme.
+use std::fmt;
+use std::str::FromStr;
+
+use slog::error;
+
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// What to do with an upgrade request that carries no `Origin` header.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MissingOriginPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl FromStr for MissingOriginPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "allow" => Ok(MissingOriginPolicy::Allow),
+            "deny" => Ok(MissingOriginPolicy::Deny),
+            _ => Err(format!("Unknown missing origin policy: {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MissingOriginPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                MissingOriginPolicy::Allow => "allow",
+                MissingOriginPolicy::Deny => "deny",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct OriginPattern {
+    scheme: Option<String>,
+    host: String,
+    port: Option<u16>,
+    wildcard: bool,
+}
+
+/// Split an origin into its scheme, host and port parts.
+fn split_origin(origin: &str) -> Option<(Option<String>, String, Option<u16>)> {
+    let origin = origin.trim().trim_end_matches('/').to_lowercase();
+    let (scheme, rest) = match origin.find("://") {
+        Some(pos) => (Some(origin[..pos].to_owned()), &origin[pos + 3..]),
+        None => (None, origin.as_str()),
+    };
+    if rest.is_empty() || rest.contains('/') {
+        return None;
+    }
+    // Leave bracketed IPv6 hosts intact.
+    let (host, port) = match rest.rfind(':') {
+        Some(pos) if !rest[pos..].contains(']') => {
+            (rest[..pos].to_owned(), Some(rest[pos + 1..].parse().ok()?))
+        }
+        _ => (rest.to_owned(), None),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((scheme, host, port))
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, host, port) =
+            split_origin(s).ok_or_else(|| format!("Invalid origin: {:?}", s))?;
+        let (host, wildcard) = match host.strip_prefix("*.") {
+            Some(domain) => (domain.to_owned(), true),
+            None => (host, false),
+        };
+        if host.contains('*') {
+            return Err(format!("Invalid origin wildcard: {:?}", s));
+        }
+        Ok(Self {
+            scheme,
+            host,
+            port,
+            wildcard,
+        })
+    }
+}
+
+impl OriginPattern {
+    fn matches(&self, scheme: &str, host: &str, port: Option<u16>) -> bool {
+        if let Some(ref want) = self.scheme {
+            if want != scheme {
+                return false;
+            }
+        }
+        if self.port != port {
+            return false;
+        }
+        if self.wildcard {
+            host.len() > self.host.len()
+                && host.ends_with(&self.host)
+                && host[..host.len() - self.host.len()].ends_with('.')
+        } else {
+            host == self.host
+        }
+    }
+}
+
+/// Describe each entry of a comma delimited `allowed_origins` list that
+/// can't be parsed.
+pub fn check_origins(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty() && !entry.eq_ignore_ascii_case("null"))
+        .filter_map(|entry| entry.parse::<OriginPattern>().err())
+        .collect()
+}
+
+/// The set of origins that may open channels.
+#[derive(Clone, Debug, Default)]
+pub struct OriginPolicy {
+    allowed: Vec<OriginPattern>,
+    /// Literal origins that aren't `scheme://host`, e.g. `null`.
+    opaque: Vec<String>,
+    pub missing: MissingOriginPolicy,
+}
+
+impl OriginPolicy {
+    pub fn new(settings: &Settings, log: &MozLogger) -> Self {
+        let mut policy = Self::default();
+        for entry in settings.allowed_origins.split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.eq_ignore_ascii_case("null") {
+                policy.opaque.push(entry.to_lowercase());
+                continue;
+            }
+            match entry.parse::<OriginPattern>() {
+                Ok(pattern) => policy.allowed.push(pattern),
+                Err(err) => error!(log.log, "Ignoring allowed origin: {}", err),
+            }
+        }
+        policy.missing = settings
+            .missing_origin
+            .parse::<MissingOriginPolicy>()
+            .unwrap_or_else(|err| {
+                error!(log.log, "{}, using default", err);
+                MissingOriginPolicy::default()
+            });
+        policy
+    }
+
+    /// Are origins being checked at all?
+    pub fn is_enabled(&self) -> bool {
+        !(self.allowed.is_empty() && self.opaque.is_empty())
+    }
+
+    /// May a request with this `Origin` header open a websocket?
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            None => return self.missing == MissingOriginPolicy::Allow,
+            Some(origin) => origin,
+        };
+        if !self.is_enabled() {
+            return true;
+        }
+        if self
+            .opaque
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin.trim()))
+        {
+            return true;
+        }
+        match split_origin(origin) {
+            Some((Some(scheme), host, port)) => self
+                .allowed
+                .iter()
+                .any(|pattern| pattern.matches(&scheme, &host, port)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(allowed: &str, missing: &str) -> OriginPolicy {
+        let settings = Settings {
+            allowed_origins: allowed.to_owned(),
+            missing_origin: missing.to_owned(),
+            ..Default::default()
+        };
+        OriginPolicy::new(&settings, &MozLogger::new_human())
+    }
+
+    #[test]
+    fn test_disabled() {
+        let policy = policy("", "allow");
+        assert!(!policy.is_enabled());
+        assert!(policy.is_allowed(Some("https://evil.example")));
+        assert!(policy.is_allowed(None));
+    }
+
+    #[test]
+    fn test_exact() {
+        let policy = policy(
+            "https://accounts.example.com, http://localhost:3030",
+            "allow",
+        );
+        assert!(policy.is_allowed(Some("https://accounts.example.com")));
+        assert!(policy.is_allowed(Some("HTTPS://Accounts.Example.com")));
+        assert!(policy.is_allowed(Some("http://localhost:3030")));
+        assert!(!policy.is_allowed(Some("http://accounts.example.com")));
+        assert!(!policy.is_allowed(Some("https://accounts.example.com:8443")));
+        assert!(!policy.is_allowed(Some("https://evil.accounts.example.com")));
+        assert!(!policy.is_allowed(Some("http://localhost")));
+        assert!(!policy.is_allowed(Some("null")));
+        assert!(!policy.is_allowed(Some("garbage")));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let policy = policy("https://*.example.com, *.example.org, null", "deny");
+        assert!(policy.is_allowed(Some("https://a.example.com")));
+        assert!(policy.is_allowed(Some("https://a.b.example.com")));
+        assert!(!policy.is_allowed(Some("https://example.com")));
+        assert!(!policy.is_allowed(Some("https://badexample.com")));
+        assert!(!policy.is_allowed(Some("http://a.example.com")));
+        assert!(policy.is_allowed(Some("http://a.example.org")));
+        assert!(policy.is_allowed(Some("moz-extension://a.example.org")));
+        assert!(policy.is_allowed(Some("null")));
+        assert!(!policy.is_allowed(None));
+    }
+
+    #[test]
+    fn test_bad_patterns() {
+        assert!("https://a.*.example.com".parse::<OriginPattern>().is_err());
+        assert!("https://example.com/path".parse::<OriginPattern>().is_err());
+        assert!("https://example.com:port".parse::<OriginPattern>().is_err());
+        assert!("https://[::1]:8000".parse::<OriginPattern>().is_ok());
+        assert!(check_origins("https://*.example.com, null,,localhost:80").is_empty());
+        assert_eq!(check_origins("https://a.*.example.com, null").len(), 1);
+    }
+}
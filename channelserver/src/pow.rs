@@ -0,0 +1,346 @@
+//! Hashcash style proof-of-work for channel creation.
+//!
+//! When enabled, a client has to solve a server-issued challenge before it
+//! may create a channel. Challenges are stateless: they carry their own
+//! expiry and difficulty, signed with an HMAC, so any server sharing
+//! `pow_secret` can check a solution.
+//!
+//! A challenge looks like `1.<expires>.<difficulty>.<nonce>.<signature>`. A
+//! solution is `<challenge>:<counter>`, where the SHA-256 hash of the whole
+//! solution string starts with at least `difficulty` zero bits.
+//!
+//! The difficulty starts at `pow_difficulty` and, if `pow_target_rate` is
+//! set, climbs by one bit for every doubling of the channel creation rate
+//! over that target, up to `pow_max_difficulty`.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sha::sha256, sign::Signer};
+use rand::{self, Rng};
+use serde::Serialize;
+
+use crate::error::{HandlerError, HandlerErrorKind};
+use crate::settings::Settings;
+
+const VERSION: &str = "1";
+/// Time constant for the channel creation rate estimate.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// A challenge to hand to the client.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u8,
+    pub expires: u64,
+    pub algorithm: &'static str,
+}
+
+#[derive(Debug)]
+struct PowState {
+    /// Decaying count of channels created over the last `RATE_WINDOW`
+    creations: f64,
+    updated: Instant,
+    /// Solutions already used, and when they can be forgotten
+    spent: HashMap<String, u64>,
+}
+
+impl PowState {
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.creations *= (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+        self.updated = now;
+    }
+}
+
+/// Issues and checks challenges. Clones share the same replay cache and
+/// creation rate.
+#[derive(Clone)]
+pub struct ProofOfWork {
+    secret: Arc<Vec<u8>>,
+    base_difficulty: u8,
+    max_difficulty: u8,
+    target_rate: u64,
+    ttl: u64,
+    state: Arc<Mutex<PowState>>,
+}
+
+impl fmt::Debug for ProofOfWork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ProofOfWork{{ base_difficulty: {}, max_difficulty: {}, target_rate: {}, ttl: {}, secret: ... }}",
+            self.base_difficulty, self.max_difficulty, self.target_rate, self.ttl,
+        )
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+            continue;
+        }
+        bits += byte.leading_zeros();
+        break;
+    }
+    bits
+}
+
+/// Does the solution string hash to at least `difficulty` leading zero bits?
+fn meets_difficulty(solution: &str, difficulty: u8) -> bool {
+    leading_zero_bits(&sha256(solution.as_bytes())) >= u32::from(difficulty)
+}
+
+impl ProofOfWork {
+    pub fn new(settings: &Settings) -> Self {
+        let secret = if settings.pow_secret.is_empty() {
+            // Only good for this process. Set `pow_secret` when running more
+            // than one server.
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill(&mut bytes);
+            bytes.to_vec()
+        } else {
+            settings.pow_secret.as_bytes().to_vec()
+        };
+        Self {
+            secret: Arc::new(secret),
+            base_difficulty: settings.pow_difficulty,
+            max_difficulty: settings.pow_max_difficulty.max(settings.pow_difficulty),
+            target_rate: settings.pow_target_rate,
+            ttl: settings.pow_ttl,
+            state: Arc::new(Mutex::new(PowState {
+                creations: 0.0,
+                updated: Instant::now(),
+                spent: HashMap::new(),
+            })),
+        }
+    }
+
+    fn sign(&self, payload: &str) -> Result<String, HandlerError> {
+        let key = PKey::hmac(&self.secret).map_err(pow_err)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(pow_err)?;
+        signer.update(payload.as_bytes()).map_err(pow_err)?;
+        let sig = signer.sign_to_vec().map_err(pow_err)?;
+        Ok(base64::encode_config(sig, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Count a newly created channel towards the creation rate.
+    pub fn record_creation(&self) {
+        self.record_creation_at(Instant::now())
+    }
+
+    fn record_creation_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.decay(now);
+        state.creations += 1.0;
+    }
+
+    /// How many leading zero bits a solution needs right now. 0 means no
+    /// proof-of-work is currently required.
+    pub fn difficulty(&self) -> u8 {
+        self.difficulty_at(Instant::now())
+    }
+
+    fn difficulty_at(&self, now: Instant) -> u8 {
+        let mut difficulty = self.base_difficulty;
+        if self.target_rate > 0 {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.decay(now);
+            let ratio = state.creations / self.target_rate as f64;
+            if ratio > 1.0 {
+                let extra = ratio.log2().floor() as u64 + 1;
+                difficulty = (u64::from(difficulty) + extra).min(255) as u8;
+            }
+        }
+        difficulty.min(self.max_difficulty)
+    }
+
+    /// Create a new challenge at the current difficulty.
+    pub fn issue(&self) -> Result<Challenge, HandlerError> {
+        self.issue_with(self.difficulty().max(1), unix_now())
+    }
+
+    fn issue_with(&self, difficulty: u8, now: u64) -> Result<Challenge, HandlerError> {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill(&mut nonce);
+        let expires = now + self.ttl;
+        let payload = format!(
+            "{}.{}.{}.{}",
+            VERSION,
+            expires,
+            difficulty,
+            base64::encode_config(nonce, base64::URL_SAFE_NO_PAD)
+        );
+        let signature = self.sign(&payload)?;
+        Ok(Challenge {
+            challenge: format!("{}.{}", payload, signature),
+            difficulty,
+            expires,
+            algorithm: "sha256",
+        })
+    }
+
+    /// Check a solution to a challenge, and mark it as spent.
+    pub fn verify(&self, solution: &str) -> Result<(), HandlerError> {
+        self.verify_at(solution, unix_now(), Instant::now())
+    }
+
+    fn verify_at(&self, solution: &str, now: u64, instant: Instant) -> Result<(), HandlerError> {
+        let (challenge, _counter) = solution
+            .rsplit_once(':')
+            .ok_or_else(|| pow_fail("Malformed solution"))?;
+        let (payload, signature) = challenge
+            .rsplit_once('.')
+            .ok_or_else(|| pow_fail("Malformed challenge"))?;
+        let expected = self.sign(payload)?;
+        if expected.len() != signature.len()
+            || !memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        {
+            return Err(pow_fail("Bad signature"));
+        }
+        let fields: Vec<&str> = payload.split('.').collect();
+        if fields.len() != 4 || fields[0] != VERSION {
+            return Err(pow_fail("Unknown challenge version"));
+        }
+        let expires: u64 = fields[1].parse().map_err(|_| pow_fail("Bad expiry"))?;
+        let difficulty: u8 = fields[2].parse().map_err(|_| pow_fail("Bad difficulty"))?;
+        if expires < now {
+            return Err(pow_fail("Challenge expired"));
+        }
+        // Challenges issued before a spike may be too easy for it.
+        if difficulty < self.difficulty_at(instant) {
+            return Err(pow_fail("Challenge too easy"));
+        }
+        if !meets_difficulty(solution, difficulty) {
+            return Err(pow_fail("Insufficient work"));
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.spent.retain(|_, expiry| *expiry >= now);
+        if state.spent.insert(challenge.to_owned(), expires).is_some() {
+            return Err(pow_fail("Challenge already used"));
+        }
+        Ok(())
+    }
+}
+
+fn pow_fail(reason: &str) -> HandlerError {
+    HandlerErrorKind::PowError(reason.to_owned()).into()
+}
+
+fn pow_err(err: openssl::error::ErrorStack) -> HandlerError {
+    HandlerErrorKind::PowError(format!("{:?}", err)).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pow(difficulty: u8, target_rate: u64) -> ProofOfWork {
+        ProofOfWork::new(&Settings {
+            pow_difficulty: difficulty,
+            pow_max_difficulty: 20,
+            pow_target_rate: target_rate,
+            pow_secret: "test secret".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .map(|counter| format!("{}:{}", challenge.challenge, counter))
+            .find(|solution| meets_difficulty(solution, challenge.difficulty))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_solve() {
+        let pow = pow(8, 0);
+        let challenge = pow.issue().unwrap();
+        assert_eq!(challenge.difficulty, 8);
+        let solution = solve(&challenge);
+        assert!(pow.verify(&solution).is_ok());
+        // Solutions can't be replayed.
+        assert!(pow.verify(&solution).is_err());
+        // Another server with the same secret accepts it.
+        let challenge = pow.issue().unwrap();
+        let solution = solve(&challenge);
+        assert!(self::pow(8, 0).verify(&solution).is_ok());
+    }
+
+    #[test]
+    fn test_bad_solutions() {
+        let pow = pow(8, 0);
+        let challenge = pow.issue().unwrap();
+        let solution = solve(&challenge);
+        // Unsolved
+        let unsolved = (0u64..)
+            .map(|counter| format!("{}:{}", challenge.challenge, counter))
+            .find(|solution| !meets_difficulty(solution, challenge.difficulty))
+            .unwrap();
+        assert!(pow.verify(&unsolved).is_err());
+        // Lowered difficulty
+        let easy = solution.replacen(".8.", ".1.", 1);
+        assert!(pow.verify(&easy).is_err());
+        // Signed by someone else
+        let mut other = self::pow(8, 0);
+        other.secret = Arc::new(b"other secret".to_vec());
+        assert!(other.verify(&solution).is_err());
+        // Garbage
+        assert!(pow.verify("").is_err());
+        assert!(pow.verify("nope").is_err());
+        assert!(pow.verify("a.b:c").is_err());
+        // Expired
+        let old = pow.issue_with(8, unix_now() - pow.ttl - 1).unwrap();
+        assert!(pow.verify(&solve(&old)).is_err());
+    }
+
+    #[test]
+    fn test_adjust_difficulty() {
+        let pow = pow(0, 10);
+        let now = Instant::now();
+        assert_eq!(pow.difficulty_at(now), 0);
+        for _ in 0..20 {
+            pow.record_creation_at(now);
+        }
+        assert_eq!(pow.difficulty_at(now), 2);
+        for _ in 0..60 {
+            pow.record_creation_at(now);
+        }
+        assert_eq!(pow.difficulty_at(now), 4);
+        // Capped at the max.
+        pow.state.lock().unwrap().creations = 1e12;
+        assert_eq!(pow.difficulty_at(now), 20);
+        // And it calms back down.
+        assert_eq!(pow.difficulty_at(now + Duration::from_secs(3600)), 0);
+        // A challenge issued before the spike is no longer good enough.
+        let pow = self::pow(1, 10);
+        let challenge = pow.issue_with(1, unix_now()).unwrap();
+        let solution = solve(&challenge);
+        for _ in 0..100 {
+            pow.record_creation_at(Instant::now());
+        }
+        assert!(pow.verify(&solution).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert_eq!(pow(0, 0).difficulty(), 0);
+    }
+}
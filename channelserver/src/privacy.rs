@@ -0,0 +1,505 @@
+//! Which of a sender's details are passed on to its peers.
+//!
+//! The deployment sets a policy with `withhold_fields`, `ip_disclosure` and
+//! `location_granularity`. The client creating a channel may tighten it for
+//! that channel with query parameters of the same names, e.g.
+//! `/v1/ws/?ip_disclosure=hash&location_granularity=country`, but can never
+//! loosen it.
+//!
+//! The same policy is applied to sender data before it's logged.
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use rand::{self, Rng};
+
+use crate::meta::SenderData;
+use crate::settings::Settings;
+
+/// The `SenderData` fields that may be withheld.
+pub const FIELDS: &[&str] = &[
+    "ua",
+    "browser",
+    "browser_version",
+    "os",
+    "device",
+    "remote",
+    "city",
+    "region",
+    "country",
+    "country_code",
+    "region_code",
+    "continent",
+    "time_zone",
+    "accuracy_radius",
+    "asn",
+    "as_org",
+];
+
+/// How much of the sender's IP address to pass on, from most to least.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum IpDisclosure {
+    #[default]
+    Full,
+    /// Only the /24 (IPv4) or /48 (IPv6) network
+    Truncate,
+    /// A keyed hash, so peers can tell addresses apart without learning them
+    Hash,
+    None,
+}
+
+impl FromStr for IpDisclosure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "full" => Ok(IpDisclosure::Full),
+            "truncate" => Ok(IpDisclosure::Truncate),
+            "hash" => Ok(IpDisclosure::Hash),
+            "none" => Ok(IpDisclosure::None),
+            _ => Err(format!("Unknown IP disclosure: {:?}", s)),
+        }
+    }
+}
+
+/// The most detailed part of the location to pass on, from most to least.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Granularity {
+    #[default]
+    City,
+    Region,
+    Country,
+    None,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "city" => Ok(Granularity::City),
+            "region" => Ok(Granularity::Region),
+            "country" => Ok(Granularity::Country),
+            "none" => Ok(Granularity::None),
+            _ => Err(format!("Unknown location granularity: {:?}", s)),
+        }
+    }
+}
+
+/// Parse a comma delimited list of `SenderData` fields to withhold.
+pub fn parse_fields(list: &str) -> Result<BTreeSet<String>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let field = field.to_lowercase();
+            if FIELDS.contains(&field.as_str()) {
+                Ok(field)
+            } else {
+                Err(format!("Unknown sender field: {:?}", field))
+            }
+        })
+        .collect()
+}
+
+/// What may not be disclosed. The default restricts nothing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Restrictions {
+    pub withhold: BTreeSet<String>,
+    pub ip: IpDisclosure,
+    pub location: Granularity,
+}
+
+impl Restrictions {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        Ok(Self {
+            withhold: parse_fields(&settings.withhold_fields)?,
+            ip: settings.ip_disclosure.parse()?,
+            location: settings.location_granularity.parse()?,
+        })
+    }
+
+    /// The restrictions a client asked for when creating a channel.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let mut restrictions = Self::default();
+        if let Some(list) = query.get("withhold_fields") {
+            restrictions.withhold = parse_fields(list)?;
+        }
+        if let Some(ip) = query.get("ip_disclosure") {
+            restrictions.ip = ip.parse()?;
+        }
+        if let Some(location) = query.get("location_granularity") {
+            restrictions.location = location.parse()?;
+        }
+        Ok(restrictions)
+    }
+
+    /// Everything either set of restrictions forbids.
+    pub fn and(&self, other: &Self) -> Self {
+        Self {
+            withhold: self.withhold.union(&other.withhold).cloned().collect(),
+            ip: self.ip.max(other.ip),
+            location: self.location.max(other.location),
+        }
+    }
+
+    fn withholds(&self, field: &str) -> bool {
+        self.withhold.contains(field)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DisclosurePolicy {
+    restrictions: Restrictions,
+    /// Key for hashed IP addresses
+    secret: Arc<Vec<u8>>,
+}
+
+impl DisclosurePolicy {
+    pub fn new(settings: &Settings) -> Self {
+        let secret = if settings.ip_hash_secret.is_empty() {
+            // Only good for this process. Set `ip_hash_secret` for hashes
+            // that stay the same across restarts and servers.
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill(&mut bytes);
+            bytes.to_vec()
+        } else {
+            settings.ip_hash_secret.as_bytes().to_vec()
+        };
+        Self {
+            restrictions: Self::restrictions(settings),
+            secret: Arc::new(secret),
+        }
+    }
+
+    /// Bad values are reported by `Settings::validate`. Should one get
+    /// through anyway, disclose as little as possible rather than too much.
+    fn restrictions(settings: &Settings) -> Restrictions {
+        Restrictions::from_settings(settings).unwrap_or_else(|_| Restrictions {
+            withhold: FIELDS.iter().map(|field| (*field).to_owned()).collect(),
+            ip: IpDisclosure::None,
+            location: Granularity::None,
+        })
+    }
+
+    /// The policy for reloaded settings, keeping the hash key.
+    pub fn reconfigure(&self, settings: &Settings) -> Self {
+        Self {
+            restrictions: Self::restrictions(settings),
+            secret: self.secret.clone(),
+        }
+    }
+
+    /// This policy, further restricted, e.g. by a channel's creator.
+    pub fn restrict(&self, restrictions: &Restrictions) -> Self {
+        Self {
+            restrictions: self.restrictions.and(restrictions),
+            secret: self.secret.clone(),
+        }
+    }
+
+    /// What of `sender` may be disclosed.
+    pub fn apply(&self, sender: &SenderData) -> SenderData {
+        let rules = &self.restrictions;
+        let keep = |field: &str, value: &Option<String>| {
+            if rules.withholds(field) {
+                None
+            } else {
+                value.clone()
+            }
+        };
+        let mut disclosed = SenderData {
+            ua: keep("ua", &sender.ua),
+            browser: keep("browser", &sender.browser),
+            browser_version: keep("browser_version", &sender.browser_version),
+            os: keep("os", &sender.os),
+            device: keep("device", &sender.device),
+            remote: self.disclose_remote(&sender.remote),
+            city: keep("city", &sender.city),
+            region: keep("region", &sender.region),
+            country: keep("country", &sender.country),
+            country_code: keep("country_code", &sender.country_code),
+            region_code: keep("region_code", &sender.region_code),
+            continent: keep("continent", &sender.continent),
+            time_zone: keep("time_zone", &sender.time_zone),
+            accuracy_radius: if rules.withholds("accuracy_radius") {
+                None
+            } else {
+                sender.accuracy_radius
+            },
+            asn: if rules.withholds("asn") {
+                None
+            } else {
+                sender.asn
+            },
+            as_org: keep("as_org", &sender.as_org),
+            ..Default::default()
+        };
+        // The accuracy radius is only meaningful for a city, and a time zone
+        // is often named after one.
+        if rules.location > Granularity::City {
+            disclosed.city = None;
+            disclosed.accuracy_radius = None;
+            disclosed.time_zone = None;
+        }
+        if rules.location > Granularity::Region {
+            disclosed.region = None;
+            disclosed.region_code = None;
+        }
+        if rules.location > Granularity::Country {
+            disclosed.country = None;
+            disclosed.country_code = None;
+            disclosed.continent = None;
+        }
+        disclosed
+    }
+
+    /// What of `sender` may be logged, keyed by log field. Only the fields
+    /// that are set are included, and never the UA string.
+    pub fn log_fields(&self, sender: &SenderData) -> Option<HashMap<String, String>> {
+        let sender = self.apply(sender);
+        let mut map: HashMap<String, String> = HashMap::new();
+        // Do not include UA string for PII reasons.
+        if let Some(val) = sender.browser {
+            map.insert("remote_browser".to_owned(), val);
+        }
+        if let Some(val) = sender.os {
+            map.insert("remote_os".to_owned(), val);
+        }
+        if let Some(val) = sender.device {
+            map.insert("remote_device".to_owned(), val);
+        }
+        if let Some(val) = sender.remote {
+            map.insert("remote_ip".to_owned(), val);
+        }
+        if let Some(val) = sender.city {
+            map.insert("remote_city".to_owned(), val);
+        }
+        if let Some(val) = sender.region {
+            map.insert("remote_region".to_owned(), val);
+        }
+        if let Some(val) = sender.country {
+            map.insert("remote_country".to_owned(), val);
+        }
+        if let Some(val) = sender.country_code {
+            map.insert("remote_country_code".to_owned(), val);
+        }
+        if let Some(val) = sender.region_code {
+            map.insert("remote_region_code".to_owned(), val);
+        }
+        if let Some(val) = sender.continent {
+            map.insert("remote_continent".to_owned(), val);
+        }
+        if let Some(val) = sender.time_zone {
+            map.insert("remote_time_zone".to_owned(), val);
+        }
+        if let Some(val) = sender.accuracy_radius {
+            map.insert("remote_accuracy_radius".to_owned(), val.to_string());
+        }
+        if let Some(val) = sender.asn {
+            map.insert("remote_asn".to_owned(), val.to_string());
+        }
+        if let Some(val) = sender.as_org {
+            map.insert("remote_as_org".to_owned(), val);
+        }
+        if !map.is_empty() {
+            return Some(map);
+        }
+        None
+    }
+
+    /// What of a sender's address may be disclosed or logged.
+    pub fn disclose_remote(&self, remote: &Option<String>) -> Option<String> {
+        if self.restrictions.withholds("remote") {
+            return None;
+        }
+        remote.as_deref().and_then(|remote| self.ip(remote))
+    }
+
+    fn ip(&self, remote: &str) -> Option<String> {
+        match self.restrictions.ip {
+            IpDisclosure::Full => Some(remote.to_owned()),
+            IpDisclosure::Truncate => {
+                let net = match remote.parse::<IpAddr>().ok()? {
+                    IpAddr::V4(addr) => IpNet::V4(Ipv4Net::new(addr, 24).ok()?),
+                    IpAddr::V6(addr) => IpNet::V6(Ipv6Net::new(addr, 48).ok()?),
+                };
+                Some(net.trunc().to_string())
+            }
+            IpDisclosure::Hash => {
+                let key = PKey::hmac(&self.secret).ok()?;
+                let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+                signer.update(remote.as_bytes()).ok()?;
+                let hash = signer.sign_to_vec().ok()?;
+                Some(base64::encode_config(&hash[..12], base64::URL_SAFE_NO_PAD))
+            }
+            IpDisclosure::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sender() -> SenderData {
+        SenderData {
+            ua: Some("Mozilla/5.0".to_owned()),
+            browser: Some("Firefox".to_owned()),
+            browser_version: Some("80".to_owned()),
+            os: Some("Windows".to_owned()),
+            device: Some("desktop".to_owned()),
+            remote: Some("63.245.208.195".to_owned()),
+            city: Some("Sacramento".to_owned()),
+            region: Some("California".to_owned()),
+            country: Some("United States".to_owned()),
+            country_code: Some("US".to_owned()),
+            region_code: Some("US-CA".to_owned()),
+            continent: Some("NA".to_owned()),
+            time_zone: Some("America/Los_Angeles".to_owned()),
+            accuracy_radius: Some(20),
+            ..Default::default()
+        }
+    }
+
+    fn policy(withhold: &str, ip: &str, location: &str) -> DisclosurePolicy {
+        DisclosurePolicy::new(&Settings {
+            withhold_fields: withhold.to_owned(),
+            ip_disclosure: ip.to_owned(),
+            location_granularity: location.to_owned(),
+            ip_hash_secret: "secret".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let disclosed = DisclosurePolicy::new(&Settings::default()).apply(&sender());
+        assert_eq!(
+            serde_json::to_value(disclosed).unwrap(),
+            serde_json::to_value(sender()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_withhold() {
+        let disclosed = policy("ua, os", "full", "city").apply(&sender());
+        assert_eq!(disclosed.ua, None);
+        assert_eq!(disclosed.os, None);
+        assert_eq!(disclosed.browser, Some("Firefox".to_owned()));
+        assert!(parse_fields("ua, planet").is_err());
+
+        let network = SenderData {
+            asn: Some(7922),
+            as_org: Some("Comcast Cable Communications, LLC".to_owned()),
+            ..Default::default()
+        };
+        let logged = policy("as_org", "full", "city")
+            .log_fields(&network)
+            .unwrap();
+        assert_eq!(logged["remote_asn"], "7922");
+        assert!(!logged.contains_key("remote_as_org"));
+        assert_eq!(
+            policy("asn, as_org", "full", "city").log_fields(&network),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ip_disclosure() {
+        let truncated = policy("", "truncate", "city").apply(&sender());
+        assert_eq!(truncated.remote, Some("63.245.208.0/24".to_owned()));
+        let v6 = SenderData {
+            remote: Some("2001:db8:1234:5678::1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy("", "truncate", "city").apply(&v6).remote,
+            Some("2001:db8:1234::/48".to_owned())
+        );
+
+        let hashed = policy("", "hash", "city").apply(&sender()).remote.unwrap();
+        assert_eq!(hashed.len(), 16);
+        assert!(!hashed.contains("63.245"));
+        // The same address always hashes the same way under one key.
+        assert_eq!(
+            policy("", "hash", "city").apply(&sender()).remote,
+            Some(hashed)
+        );
+        assert_eq!(policy("", "none", "city").apply(&sender()).remote, None);
+        assert!("partial".parse::<IpDisclosure>().is_err());
+
+        // Logged addresses follow the same policy.
+        let remote = sender().remote;
+        assert_eq!(
+            policy("", "truncate", "city").disclose_remote(&remote),
+            Some("63.245.208.0/24".to_owned())
+        );
+        assert_eq!(
+            policy("remote", "full", "city").disclose_remote(&remote),
+            None
+        );
+        let logged = policy("", "hash", "city").log_fields(&sender()).unwrap();
+        assert_eq!(logged["remote_ip"].len(), 16);
+        assert!(!logged.contains_key("remote_ua"));
+    }
+
+    #[test]
+    fn test_location_granularity() {
+        let region = policy("", "full", "region").apply(&sender());
+        assert_eq!(region.city, None);
+        assert_eq!(region.accuracy_radius, None);
+        assert_eq!(region.region, Some("California".to_owned()));
+        // e.g. "America/Los_Angeles" names a city.
+        assert_eq!(region.time_zone, None);
+        assert_eq!(region.region_code, Some("US-CA".to_owned()));
+        let country = policy("", "full", "country").apply(&sender());
+        assert_eq!(country.region, None);
+        assert_eq!(country.region_code, None);
+        assert_eq!(country.time_zone, None);
+        assert_eq!(country.country, Some("United States".to_owned()));
+        assert_eq!(country.country_code, Some("US".to_owned()));
+        let none = policy("", "full", "none").apply(&sender());
+        assert_eq!(none.country, None);
+        assert_eq!(none.continent, None);
+    }
+
+    #[test]
+    fn test_restrict() {
+        let deployment = policy("ua", "truncate", "region");
+        let mut query = HashMap::new();
+        query.insert("ip_disclosure".to_owned(), "full".to_owned());
+        query.insert("location_granularity".to_owned(), "country".to_owned());
+        query.insert("withhold_fields".to_owned(), "device".to_owned());
+        let channel = Restrictions::from_query(&query).unwrap();
+        let disclosed = deployment.restrict(&channel).apply(&sender());
+        // A channel can't loosen the deployment's policy...
+        assert_eq!(disclosed.remote, Some("63.245.208.0/24".to_owned()));
+        assert_eq!(disclosed.ua, None);
+        // ...only tighten it.
+        assert_eq!(disclosed.region, None);
+        assert_eq!(disclosed.device, None);
+
+        query.insert("ip_disclosure".to_owned(), "partial".to_owned());
+        assert!(Restrictions::from_query(&query).is_err());
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let original = DisclosurePolicy::new(&Settings {
+            ip_disclosure: "hash".to_owned(),
+            ..Default::default()
+        });
+        let hashed = original.apply(&sender()).remote;
+        let reloaded = original.reconfigure(&Settings {
+            ip_disclosure: "hash".to_owned(),
+            location_granularity: "country".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(reloaded.apply(&sender()).remote, hashed);
+        assert_eq!(reloaded.apply(&sender()).city, None);
+    }
+}
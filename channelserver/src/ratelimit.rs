@@ -0,0 +1,230 @@
+//! Per-session rate limiting for client messages.
+//!
+//! Each session gets a pair of token buckets, one counting messages and one
+//! counting octets. A bucket holds one second's worth of its rate, so a
+//! client may burst up to the configured rate, and then is held to it.
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::settings::Settings;
+
+/// The longest a message will be held back when delaying. Anything that would
+/// need to wait longer is dropped instead.
+pub const MAX_RATE_DELAY: Duration = Duration::from_secs(1);
+
+/// What to do with a message that arrives faster than allowed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RateLimitAction {
+    /// Silently discard the message.
+    #[default]
+    Drop,
+    /// Hold the message until the session is back under its limits.
+    Delay,
+    /// Close the session.
+    Disconnect,
+}
+
+impl FromStr for RateLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop" => Ok(RateLimitAction::Drop),
+            "delay" => Ok(RateLimitAction::Delay),
+            "disconnect" => Ok(RateLimitAction::Disconnect),
+            _ => Err(format!("Unknown rate limit action: {:?}", s)),
+        }
+    }
+}
+
+impl RateLimitAction {
+    /// What is actually done with a message that would have to wait `wait`
+    /// to be under the limits: messages that would be held too long are
+    /// dropped instead.
+    pub fn resolve(self, wait: Duration) -> Self {
+        match self {
+            RateLimitAction::Delay if wait > MAX_RATE_DELAY => RateLimitAction::Drop,
+            action => action,
+        }
+    }
+}
+
+impl fmt::Display for RateLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                RateLimitAction::Drop => "drop",
+                RateLimitAction::Delay => "delay",
+                RateLimitAction::Disconnect => "disconnect",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+        let rate = rate as f64;
+        Some(Self {
+            rate,
+            tokens: rate,
+            last: now,
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// How long until `cost` tokens may be taken. A full bucket always admits,
+    /// so a single item larger than the rate is not blocked forever.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.rate);
+        if self.tokens >= needed {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64((needed - self.tokens) / self.rate)
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost;
+    }
+}
+
+/// Messages-per-second and octets-per-second limits for one session.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    data: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &Settings) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: TokenBucket::new(settings.max_msg_rate, now),
+            data: TokenBucket::new(settings.max_data_rate, now),
+        }
+    }
+
+    /// How long a message of `len` octets would have to wait before it can be
+    /// relayed. A zero duration means it may go now.
+    pub fn delay_for(&mut self, len: usize, now: Instant) -> Duration {
+        let mut wait = Duration::from_secs(0);
+        if let Some(ref mut bucket) = self.messages {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time(1.0));
+        }
+        if let Some(ref mut bucket) = self.data {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time(len as f64));
+        }
+        wait
+    }
+
+    /// Charge a message of `len` octets against the limits. If the message
+    /// is being delayed, this leaves the buckets in debt so that messages
+    /// behind it wait their turn.
+    pub fn consume(&mut self, len: usize) {
+        if let Some(ref mut bucket) = self.messages {
+            bucket.take(1.0);
+        }
+        if let Some(ref mut bucket) = self.data {
+            bucket.take(len as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn millis(wait: Duration) -> u64 {
+        (wait.as_secs_f64() * 1000.0).round() as u64
+    }
+
+    fn limiter(max_msg_rate: u64, max_data_rate: u64) -> RateLimiter {
+        RateLimiter::new(&Settings {
+            max_msg_rate,
+            max_data_rate,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut limiter = limiter(0, 0);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(limiter.delay_for(100_000, now), Duration::from_secs(0));
+            limiter.consume(100_000);
+        }
+    }
+
+    #[test]
+    fn test_message_rate() {
+        let mut limiter = limiter(2, 0);
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(limiter.delay_for(1, now), Duration::from_secs(0));
+            limiter.consume(1);
+        }
+        assert_eq!(millis(limiter.delay_for(1, now)), 500);
+        // half a second later, there's room for one more.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.delay_for(1, later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_data_rate() {
+        let mut limiter = limiter(0, 100);
+        let now = Instant::now();
+        assert_eq!(limiter.delay_for(60, now), Duration::from_secs(0));
+        limiter.consume(60);
+        assert_eq!(millis(limiter.delay_for(60, now)), 200);
+        // Oversized messages are only admitted from a full bucket.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.delay_for(500, later), Duration::from_secs(0));
+        limiter.consume(500);
+        assert_eq!(millis(limiter.delay_for(1, later)), 4010);
+    }
+
+    #[test]
+    fn test_delay_queues() {
+        let mut limiter = limiter(1, 0);
+        let now = Instant::now();
+        limiter.consume(1);
+        let first = limiter.delay_for(1, now);
+        limiter.consume(1);
+        let second = limiter.delay_for(1, now);
+        assert_eq!(millis(first), 1000);
+        assert_eq!(millis(second), 2000);
+    }
+
+    #[test]
+    fn test_action() {
+        assert_eq!(
+            "Disconnect".parse::<RateLimitAction>(),
+            Ok(RateLimitAction::Disconnect)
+        );
+        assert_eq!(
+            " delay".parse::<RateLimitAction>(),
+            Ok(RateLimitAction::Delay)
+        );
+        assert!("explode".parse::<RateLimitAction>().is_err());
+    }
+}
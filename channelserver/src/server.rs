@@ -3,29 +3,47 @@
 //! channels through `ChannelServer`.
 use std::collections::{hash_map::Entry, HashMap};
 use std::fmt;
-use std::time::Instant;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
-use actix::prelude::{Actor, Context, Handler, Message, MessageResult, Recipient};
+use actix::prelude::{Actor, AsyncContext, Context, Handler, Message, MessageResult, Recipient};
+use actix_web_actors::ws::CloseCode;
 use cadence::{Counted, StatsdClient};
+use openssl::memcmp;
 use rand::{self, rngs::ThreadRng, Rng};
 use serde::Serialize;
 use serde_json::json;
-use slog::{debug, error, trace, warn};
+use slog::{debug, error, info, trace, warn};
 
+use crate::abuse::{AbuseLedger, Infraction};
 use crate::channelid::ChannelID;
 use crate::error as perror;
-use crate::logging;
+use crate::language::Languages;
+use crate::links::Links;
 use crate::logging::MozLogger;
 use crate::meta;
 use crate::metrics;
+use crate::pow::ProofOfWork;
+use crate::privacy::{DisclosurePolicy, Restrictions};
 use crate::settings::Settings;
 
 pub const EOL: &str = "\x04";
+/// Number of principal parties in a channel, the auth and the supplicant.
+const PRINCIPALS: usize = 2;
+/// How often to forget about remotes whose abuse scores have decayed away.
+const ABUSE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to look for channels that have outlived `conn_lifespan`.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Serialize, Debug, PartialEq)]
 pub enum MessageType {
     Text,
     Terminate,
+    /// Close the connection, telling the client why.
+    Close(DisconnectReason),
 }
 
 /// New session is created
@@ -36,6 +54,46 @@ pub struct Connect {
     pub channel: ChannelID,
     pub remote: Option<String>,
     pub initial_connect: bool,
+    /// Token issued to a principal when it first joined the channel
+    pub token: Option<String>,
+    /// What the creator of a new channel asked to keep from its peers
+    pub restrictions: Restrictions,
+    /// The languages the client reads, for what it's told about its peers
+    pub langs: Languages,
+    /// The client's autonomous system number and organization, for the logs
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+/// New settings have been loaded. Sent to the `ChannelServer`, which passes
+/// them on to every registered worker.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct UpdateSettings(pub Settings);
+
+/// A worker wants to hear about new settings
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RegisterWorker(pub Recipient<UpdateSettings>);
+
+/// Close every channel, e.g. because the server is going away.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseAll(pub DisconnectReason);
+
+/// Whether the server is draining before a shutdown. A draining server
+/// accepts no new channels. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct DrainState(Arc<AtomicBool>);
+
+impl DrainState {
+    pub fn start(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 /// Session is disconnected
@@ -47,11 +105,26 @@ pub struct Disconnect {
     pub reason: DisconnectReason,
 }
 
-#[derive(Serialize, Debug, PartialEq, PartialOrd)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum DisconnectReason {
     None,
     _ConnectionError,
     Timeout,
+    RateLimited,
+    Expired,
+    ServerShutdown,
+}
+
+impl DisconnectReason {
+    /// The websocket close code to send the client.
+    pub fn close_code(self) -> CloseCode {
+        match self {
+            DisconnectReason::RateLimited => CloseCode::Policy,
+            DisconnectReason::_ConnectionError => CloseCode::Error,
+            DisconnectReason::ServerShutdown => CloseCode::Away,
+            _ => CloseCode::Normal,
+        }
+    }
 }
 
 impl fmt::Display for DisconnectReason {
@@ -63,6 +136,10 @@ impl fmt::Display for DisconnectReason {
                 DisconnectReason::None => "Client Disconnect",
                 DisconnectReason::_ConnectionError => "Connection Error",
                 DisconnectReason::Timeout => "Connection Timeout",
+                DisconnectReason::RateLimited => "Rate Limit Exceeded",
+                DisconnectReason::Expired => "Channel Expired",
+                // Clients may reconnect with their tokens to another server.
+                DisconnectReason::ServerShutdown => "Server Going Away; Retry",
             }
         )
     }
@@ -94,10 +171,83 @@ pub struct ClientMessage {
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Channel {
     pub session_id: SessionId,
+    /// When the channel this session belongs to was created
     pub started: Instant,
-    pub msg_count: u8,
-    pub data_exchanged: usize,
+    /// What this participant has sent into the channel
+    pub sent: Usage,
     pub remote: Option<String>,
+    /// The languages this participant reads
+    pub langs: Languages,
+}
+
+/// What the server tracks for each channel as a whole.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct ChannelInfo {
+    /// Messages and data relayed through the channel
+    pub usage: Usage,
+    /// Tokens issued to the channel's principals, in the order they joined
+    pub principals: Vec<String>,
+    /// Sender details the channel's creator asked to keep from its peers
+    pub restrictions: Restrictions,
+}
+
+/// Running totals of relayed client messages, kept both for each channel and
+/// for each sender within it.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct Usage {
+    pub msg_count: u64,
+    pub data_exchanged: u64,
+}
+
+/// Which limit a message would break.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum LimitExceeded {
+    Messages,
+    Data,
+}
+
+/// Limits checked against a `Usage`. A value of 0 means unlimited.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct UsageLimits {
+    pub max_exchanges: u64,
+    pub max_data: u64,
+}
+
+impl UsageLimits {
+    /// Limits for everything relayed through a channel.
+    pub fn channel(settings: &Settings) -> Self {
+        Self {
+            max_exchanges: settings.max_exchanges.into(),
+            max_data: settings.max_data,
+        }
+    }
+
+    /// Limits for everything a single participant sends.
+    pub fn sender(settings: &Settings) -> Self {
+        Self {
+            max_exchanges: settings.max_sender_exchanges.into(),
+            max_data: settings.max_sender_data,
+        }
+    }
+}
+
+impl Usage {
+    /// Would relaying a message of `len` octets go over `limits`?
+    pub fn check(&self, len: usize, limits: &UsageLimits) -> Result<(), LimitExceeded> {
+        if limits.max_exchanges > 0 && self.msg_count >= limits.max_exchanges {
+            return Err(LimitExceeded::Messages);
+        }
+        if limits.max_data > 0 && self.data_exchanged.saturating_add(len as u64) > limits.max_data {
+            return Err(LimitExceeded::Data);
+        }
+        Ok(())
+    }
+
+    /// Count a relayed message of `len` octets.
+    pub fn record(&mut self, len: usize) {
+        self.msg_count = self.msg_count.saturating_add(1);
+        self.data_exchanged = self.data_exchanged.saturating_add(len as u64);
+    }
 }
 
 /// List of available rooms
@@ -112,6 +262,8 @@ impl actix::Message for ListChannels {
 pub struct ChannelServer {
     // collections of sessions grouped by channel
     channels: HashMap<ChannelID, Channels>,
+    // usage and principals of each channel
+    info: HashMap<ChannelID, ChannelInfo>,
     // individual connections
     sessions: HashMap<SessionId, Recipient<TextMessage>>,
     // random number generator
@@ -121,6 +273,17 @@ pub struct ChannelServer {
     // configuration options
     pub settings: Settings,
     pub metrics: StatsdClient,
+    // per IP abuse scores, shared with the sessions
+    pub abuse: AbuseLedger,
+    // channel creation proof-of-work, shared with the routes
+    pub pow: ProofOfWork,
+    // workers to pass new settings on to
+    workers: Vec<Recipient<UpdateSettings>>,
+    // links handed to clients when they join
+    links: Links,
+    // what peers are told about a message's sender, and what's logged of
+    // it; shared with the routes, so hashed addresses match
+    pub privacy: DisclosurePolicy,
 }
 
 impl ChannelServer {
@@ -128,62 +291,107 @@ impl ChannelServer {
         let metrics = metrics::metrics_from_opts(settings, log).expect("Could not create metrics");
         // Add the known private networks to the trusted proxy list
 
+        let privacy = DisclosurePolicy::new(settings);
+        let abuse = AbuseLedger::new(settings, log, &metrics, &privacy);
         Self {
             sessions: HashMap::new(),
             channels: HashMap::new(),
+            info: HashMap::new(),
             rng: ThreadRng::default(),
             log: log.clone(),
             settings: settings.clone(),
             metrics,
+            abuse,
+            pow: ProofOfWork::new(settings),
+            workers: Vec::new(),
+            links: Links::new(settings),
+            privacy,
         }
     }
 
     /// Send message to all users in the room
-    fn send_message(
+    ///
+    /// Each participant is sent the message as `render` gives it for the
+    /// participant's languages, rendered once for each set of languages. It is
+    /// counted, at the `size` of the client's payload, once against the
+    /// channel totals and once against the sender's own totals, no matter how
+    /// many participants receive it.
+    fn send_message<F>(
         &mut self,
         channel: &ChannelID,
-        message: &str,
-        skip_id: SessionId,
-    ) -> Result<(), perror::HandlerError> {
-        if let Some(participants) = self.channels.get_mut(channel) {
-            for party in participants.values_mut() {
-                let max_data: usize = self.settings.max_data as usize;
-                let msg_len = message.len();
-                let remote_ip = party.remote.clone().unwrap_or_else(|| "Unknown".to_owned());
-                if max_data > 0 && (party.data_exchanged > max_data || msg_len > max_data) {
-                    warn!(
-                        self.log.log,
-                        "Too much data sent through {}, closing", channel;
-                        "remote_ip" => &remote_ip
-                    );
-                    self.metrics.incr("conn.max.data").ok();
-                    let mut remote = "";
-                    if let Some(ref rr) = party.remote {
-                        remote = rr;
-                    }
-                    return Err(perror::HandlerErrorKind::XSDataErr(remote.to_owned()).into());
-                }
-                party.data_exchanged += msg_len;
-                let msg_count = self.settings.max_exchanges;
-                party.msg_count += 1;
-                if msg_count > 0 && party.msg_count > msg_count {
-                    warn!(
-                        self.log.log,
-                        "Too many messages through {}, closing", channel;
-                        "remote_ip" => &remote_ip
-                    );
-                    let mut remote = "";
-                    if let Some(ref rr) = party.remote {
-                        remote = rr;
-                    }
-                    self.metrics.incr("conn.max.msg").ok();
-                    return Err(perror::HandlerErrorKind::XSMessageErr(remote.to_owned()).into());
-                }
-                if party.session_id != skip_id {
-                    if let Some(addr) = self.sessions.get(&party.session_id) {
-                        addr.do_send(TextMessage(MessageType::Text, message.to_owned()))
-                            .ok();
-                    }
+        sender_id: SessionId,
+        size: usize,
+        render: F,
+    ) -> Result<(), perror::HandlerError>
+    where
+        F: Fn(&Languages) -> String,
+    {
+        let participants = match self.channels.get_mut(channel) {
+            Some(participants) => participants,
+            None => return Ok(()),
+        };
+        let usage = &mut self.info.entry(*channel).or_default().usage;
+        let sender = match participants.get_mut(&sender_id) {
+            Some(sender) => sender,
+            None => {
+                warn!(
+                    self.log.log,
+                    "Message from non-participant dropped";
+                    "channel" => channel.as_string(),
+                    "session" => sender_id,
+                );
+                return Ok(());
+            }
+        };
+        let remote = self
+            .privacy
+            .disclose_remote(&sender.remote)
+            .unwrap_or_default();
+        let channel_limits = UsageLimits::channel(&self.settings);
+        let sender_limits = UsageLimits::sender(&self.settings);
+        match usage
+            .check(size, &channel_limits)
+            .and_then(|_| sender.sent.check(size, &sender_limits))
+        {
+            Err(LimitExceeded::Data) => {
+                warn!(
+                    self.log.log,
+                    "Too much data sent through {}, closing", channel;
+                    "remote_ip" => &remote,
+                    "session" => sender_id,
+                );
+                self.metrics.incr("conn.max.data").ok();
+                self.abuse.record(&sender.remote, Infraction::LimitExceeded);
+                return Err(perror::HandlerErrorKind::XSDataErr(remote).into());
+            }
+            Err(LimitExceeded::Messages) => {
+                warn!(
+                    self.log.log,
+                    "Too many messages through {}, closing", channel;
+                    "remote_ip" => &remote,
+                    "session" => sender_id,
+                );
+                self.metrics.incr("conn.max.msg").ok();
+                self.abuse.record(&sender.remote, Infraction::LimitExceeded);
+                return Err(perror::HandlerErrorKind::XSMessageErr(remote).into());
+            }
+            Ok(()) => {}
+        }
+        usage.record(size);
+        sender.sent.record(size);
+        let mut rendered: Vec<(&Languages, String)> = Vec::new();
+        for party in participants.values() {
+            if party.session_id != sender_id {
+                if let Some(addr) = self.sessions.get(&party.session_id) {
+                    let text = match rendered.iter().find(|(langs, _)| *langs == &party.langs) {
+                        Some((_, text)) => text.clone(),
+                        None => {
+                            let text = render(&party.langs);
+                            rendered.push((&party.langs, text.clone()));
+                            text
+                        }
+                    };
+                    addr.do_send(TextMessage(MessageType::Text, text)).ok();
                 }
             }
         }
@@ -211,48 +419,89 @@ impl ChannelServer {
             }
         }
         if do_shutdown {
-            self.shutdown(channel);
+            self.shutdown(channel, DisconnectReason::None);
         }
     }
 
     /// Kill a channel and terminate all participants.
     ///
     /// This sends a Terminate to each participant, which forces the connection closed.
-    fn shutdown(&mut self, channel: &ChannelID) {
+    /// If there is a reason other than `None`, the participants are sent a
+    /// close frame with it.
+    fn shutdown(&mut self, channel: &ChannelID, reason: DisconnectReason) {
         if let Some(participants) = self.channels.get(channel) {
             for id in participants.keys() {
-                if let Some(addr) = self.sessions.get(&id) {
+                if let Some(addr) = self.sessions.get(id) {
                     // send a control message to force close
-                    addr.do_send(TextMessage(MessageType::Terminate, EOL.to_owned()))
-                        .ok();
+                    let message_type = match reason {
+                        DisconnectReason::None => MessageType::Terminate,
+                        _ => MessageType::Close(reason),
+                    };
+                    addr.do_send(TextMessage(message_type, EOL.to_owned())).ok();
                 }
-                self.sessions.remove(&id);
+                self.sessions.remove(id);
             }
         }
-        debug!(self.log.log, "Removing channel {}", channel);
+        debug!(self.log.log, "Removing channel {}", channel; "reason" => reason.to_string());
         self.channels.remove(channel);
+        self.info.remove(channel);
     }
-}
 
-/// Is a previously connected client trying to reconnect?
-fn reconnect_check(
-    group: &Channels,
-    new_remote: &Option<String>,
-    log: Option<&logging::MozLogger>,
-) -> bool {
-    if let Some(ref req_ip) = new_remote {
-        for participant in group.values() {
-            if let Some(log) = log {
-                debug!(log.log, "Checking {:?}", &participant.remote);
-            }
-            if let Some(ref loc_ip) = &participant.remote {
-                if req_ip == loc_ip {
-                    return true;
-                }
-            }
+    /// Close every channel that was created more than `conn_lifespan`
+    /// seconds before `now`.
+    fn expire_channels(&mut self, now: Instant) {
+        if self.settings.conn_lifespan == 0 {
+            return;
+        }
+        let lifespan = Duration::from_secs(self.settings.conn_lifespan);
+        let expired: Vec<ChannelID> = self
+            .channels
+            .iter()
+            .filter(|(_, participants)| {
+                participants
+                    .values()
+                    .any(|party| now.saturating_duration_since(party.started) >= lifespan)
+            })
+            .map(|(channel, _)| *channel)
+            .collect();
+        for channel in expired {
+            info!(
+                self.log.log,
+                "Channel lifespan expired";
+                "channel" => channel.as_string(),
+            );
+            self.metrics.incr("conn.expired").ok();
+            self.shutdown(&channel, DisconnectReason::Expired);
+        }
+    }
+
+    /// Close every channel, sending each participant the reason.
+    fn close_all(&mut self, reason: DisconnectReason) {
+        let channels: Vec<ChannelID> = self.channels.keys().cloned().collect();
+        info!(
+            self.log.log,
+            "Closing all channels";
+            "channels" => channels.len(),
+            "reason" => reason.to_string(),
+        );
+        for channel in channels {
+            self.shutdown(&channel, reason);
         }
     }
-    false
+}
+
+/// Create a new principal token.
+fn new_token<R: Rng>(rng: &mut R) -> String {
+    let mut bytes = [0u8; 16];
+    rng.fill(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Was this token issued to one of the channel's principals?
+fn token_check(principals: &[String], token: &str) -> bool {
+    principals.iter().any(|issued| {
+        issued.len() == token.len() && memcmp::eq(issued.as_bytes(), token.as_bytes())
+    })
 }
 
 /// Handler for Disconnect message.
@@ -271,6 +520,45 @@ impl Handler<Disconnect> for ChannelServer {
     }
 }
 
+/// Handler for UpdateSettings message.
+impl Handler<UpdateSettings> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateSettings, _ctx: &mut Context<Self>) {
+        // Workers that have gone away are dropped.
+        self.workers
+            .retain(|worker| worker.do_send(msg.clone()).is_ok());
+        info!(
+            self.log.log,
+            "Settings reloaded";
+            "workers" => self.workers.len(),
+        );
+        self.metrics.incr("settings.reload").ok();
+        self.links = Links::new(&msg.0);
+        self.privacy = self.privacy.reconfigure(&msg.0);
+        self.abuse.set_privacy(&self.privacy);
+        self.settings = msg.0;
+    }
+}
+
+/// Handler for CloseAll message.
+impl Handler<CloseAll> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseAll, _ctx: &mut Context<Self>) {
+        self.close_all(msg.0);
+    }
+}
+
+/// Handler for RegisterWorker message.
+impl Handler<RegisterWorker> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterWorker, _ctx: &mut Context<Self>) {
+        self.workers.push(msg.0);
+    }
+}
+
 /// Handler for Message message.
 impl Handler<ClientMessage> for ChannelServer {
     type Result = ();
@@ -279,19 +567,23 @@ impl Handler<ClientMessage> for ChannelServer {
         if msg.message_type == MessageType::Terminate {
             return self.disconnect(&msg.channel, msg.id);
         }
+        let policy = match self.info.get(&msg.channel) {
+            Some(info) => self.privacy.restrict(&info.restrictions),
+            None => self.privacy.clone(),
+        };
+        // Each peer is told where the sender is in its own language.
+        let render = |langs: &Languages| {
+            json!({
+                "message": &msg.msg,
+                "sender": policy.apply(&msg.sender.localized(langs)),
+            })
+            .to_string()
+        };
         if self
-            .send_message(
-                &msg.channel,
-                &json!({
-                    "message": &msg.msg,
-                    "sender": &msg.sender,
-                })
-                .to_string(),
-                msg.id,
-            )
+            .send_message(&msg.channel, msg.id, msg.msg.len(), render)
             .is_err()
         {
-            self.shutdown(&msg.channel)
+            self.shutdown(&msg.channel, DisconnectReason::None)
         }
     }
 }
@@ -301,6 +593,16 @@ impl Actor for ChannelServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.abuse.is_enabled() {
+            ctx.run_interval(ABUSE_PRUNE_INTERVAL, |act, _ctx| act.abuse.prune());
+        }
+        // `conn_lifespan` may be turned on by a reload, so always check.
+        ctx.run_interval(EXPIRY_CHECK_INTERVAL, |act, _ctx| {
+            act.expire_channels(Instant::now())
+        });
+    }
 }
 
 /// Handler for Connect message.
@@ -311,14 +613,21 @@ impl Handler<Connect> for ChannelServer {
 
     fn handle(&mut self, msg: Connect, _ctx: &mut Context<Self>) -> Self::Result {
         let session_id = self.rng.gen::<usize>();
-        let remote = &msg.remote.clone().unwrap_or_else(|| "Unkown".to_owned());
+        let remote = &self.privacy.disclose_remote(&msg.remote);
         let chan_id = &msg.channel.as_string();
+        // Joining sessions share the channel's start, so that the lifespan
+        // runs from when the channel was first created.
+        let started = self
+            .channels
+            .get(&msg.channel)
+            .and_then(|group| group.values().map(|party| party.started).min())
+            .unwrap_or_else(Instant::now);
         let new_session = Channel {
             session_id,
-            started: Instant::now(),
-            msg_count: 0,
-            data_exchanged: 0,
+            started,
+            sent: Usage::default(),
             remote: msg.remote.clone(),
+            langs: msg.langs.clone(),
         };
         self.sessions
             .insert(new_session.session_id, msg.addr.clone());
@@ -330,6 +639,7 @@ impl Handler<Connect> for ChannelServer {
             "remote_ip" => remote,
         );
         // Is this a new channel request?
+        let created = !self.channels.contains_key(&msg.channel);
         if let Entry::Vacant(entry) = self.channels.entry(msg.channel) {
             // Is this the first time we're requesting this channel?
             if !&msg.initial_connect {
@@ -339,9 +649,33 @@ impl Handler<Connect> for ChannelServer {
                     "channel" => chan_id,
                     "remote_ip" => remote,
                 );
+                self.sessions.remove(&session_id);
+                self.abuse.record(&msg.remote, Infraction::UnknownChannel);
+                return 0;
+            }
+            // A new channel has issued no tokens yet, so any presented for it
+            // is invalid. Refuse it before anything is set up for the channel.
+            if msg.token.is_some() {
+                warn!(
+                    self.log.log,
+                    "Invalid reconnection token";
+                    "channel" => chan_id,
+                    "remote_ip" => remote,
+                );
+                self.sessions.remove(&session_id);
+                self.metrics.incr("conn.bad_token").ok();
+                self.abuse.record(&msg.remote, Infraction::InvalidToken);
                 return 0;
             }
             entry.insert(HashMap::new());
+            self.info.insert(
+                msg.channel,
+                ChannelInfo {
+                    restrictions: msg.restrictions.clone(),
+                    ..Default::default()
+                },
+            );
+            self.pow.record_creation();
         };
         let group = match self.channels.get_mut(&msg.channel) {
             None => {
@@ -349,6 +683,7 @@ impl Handler<Connect> for ChannelServer {
                 "No group information found for channel";
                 "channel" => chan_id,
                 "remote_ip" => remote);
+                self.sessions.remove(&session_id);
                 return 0;
             }
             Some(v) => v,
@@ -363,37 +698,79 @@ impl Handler<Connect> for ChannelServer {
             self.sessions.remove(&new_session.session_id);
             self.metrics.incr("conn.max.conn").ok();
             // It doesn't make sense to impose a high penalty for this
-            // behavior, but we may want to flag and log the origin
-            // IP for later analytics.
-            // We could also impose a tiny penalty on the IP (if possible)
-            // which would minimally impact accidental occurances, but
-            // add up for major infractors.
+            // behavior, so only a tiny penalty is imposed on the IP.
+            // This minimally impacts accidental occurances, but adds up
+            // for major infractors.
+            self.abuse.record(&msg.remote, Infraction::ChannelFull);
             return 0;
         }
-        // The group should have two principle parties, the auth and supplicant
-        // Any connection beyond that group should be checked to ensure it's
-        // from a known IP. If a principle that only has one connection and it
-        // drops, it is possible that it can't reconnect, but that's not a bad
-        // thing. We should just let the connection expire as invalid so that
-        // it's not stolen.
-        if group.len() > 2 && !reconnect_check(&group, &new_session.remote, Some(&self.log)) {
-            error!(
-                self.log.log,
-                "Unexpected remote connection";
-                "remote_ip" => remote,
-            );
-            return 0;
+        // The group should have two principal parties, the auth and
+        // supplicant. Each is issued a token when it joins, and has to present
+        // it to reconnect. Once both principals have joined, the channel is
+        // locked in, and connections without a valid token are refused, no
+        // matter where they come from.
+        let info = self.info.entry(msg.channel).or_default();
+        let token = match msg.token {
+            Some(ref token) => {
+                if !token_check(&info.principals, token) {
+                    warn!(
+                        self.log.log,
+                        "Invalid reconnection token";
+                        "channel" => chan_id,
+                        "remote_ip" => remote,
+                    );
+                    self.sessions.remove(&session_id);
+                    self.metrics.incr("conn.bad_token").ok();
+                    self.abuse.record(&msg.remote, Infraction::InvalidToken);
+                    return 0;
+                }
+                token.clone()
+            }
+            None => {
+                if info.principals.len() >= PRINCIPALS {
+                    error!(
+                        self.log.log,
+                        "Unexpected remote connection";
+                        "channel" => chan_id,
+                        "remote_ip" => remote,
+                    );
+                    self.sessions.remove(&session_id);
+                    self.metrics.incr("conn.locked").ok();
+                    return 0;
+                }
+                let token = new_token(&mut self.rng);
+                info.principals.push(token.clone());
+                token
+            }
         };
+        // What's logged of the client follows the channel's disclosure policy.
+        let logged = self
+            .privacy
+            .restrict(&info.restrictions)
+            .log_fields(&meta::SenderData {
+                remote: msg.remote.clone(),
+                asn: msg.asn,
+                as_org: msg.as_org.clone(),
+                ..Default::default()
+            })
+            .unwrap_or_default();
         debug!(self.log.log,
             "Adding session to channel";
             "channel" => chan_id,
             "session" => &new_session.session_id,
-            "remote_ip" => remote,
+            "remote_ip" => logged.get("remote_ip"),
+        );
+        info!(self.log.log,
+            "Session joined channel";
+            "channel" => chan_id,
+            "session" => &new_session.session_id,
+            "created" => created,
+            "asn" => logged.get("remote_asn"),
+            "as_org" => logged.get("remote_as_org"),
         );
         group.insert(session_id, new_session);
         // tell the client what their channel is.
-        let jpath = json!({ "link": format!("/v1/ws/{}", chan_id),
-                            "channelid": chan_id });
+        let jpath = self.links.welcome(chan_id, &token);
         if msg
             .addr
             .do_send(TextMessage(MessageType::Text, jpath.to_string()))
@@ -429,33 +806,385 @@ impl Handler<ListChannels> for ChannelServer {
 mod test {
     use super::*;
 
+    use crate::geo::{Location, Name};
+    use crate::privacy::Granularity;
+
     #[test]
-    fn test_reconnect() {
-        let mut test_group: Channels = HashMap::new();
+    fn test_token_check() {
+        let mut rng = ThreadRng::default();
+        let principals = vec![new_token(&mut rng), new_token(&mut rng)];
+        assert_ne!(principals[0], principals[1]);
+        assert!(token_check(&principals, &principals[0]));
+        assert!(token_check(&principals, &principals[1]));
+        assert!(!token_check(&principals, &new_token(&mut rng)));
+        assert!(!token_check(&principals, ""));
+        assert!(!token_check(&principals, &principals[0][1..]));
+        assert!(!token_check(&[], &principals[0]));
+    }
 
-        test_group.insert(
-            1,
-            Channel {
-                session_id: 1,
-                started: Instant::now(),
-                msg_count: 0,
-                data_exchanged: 0,
-                remote: Some("127.0.0.1".to_owned()),
-            },
+    /// Stands in for a session, remembering what it was sent.
+    #[derive(Default)]
+    struct Collector {
+        messages: Vec<String>,
+    }
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<TextMessage> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: TextMessage, _: &mut Context<Self>) {
+            self.messages.push(msg.1);
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<String>")]
+    struct Collected;
+
+    impl Handler<Collected> for Collector {
+        type Result = MessageResult<Collected>;
+
+        fn handle(&mut self, _: Collected, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.messages.clone())
+        }
+    }
+
+    /// Connect to the channel, returning the session id and issued token.
+    async fn connect(
+        server: &actix::Addr<ChannelServer>,
+        channel: ChannelID,
+        initial_connect: bool,
+        remote: &str,
+        token: Option<String>,
+    ) -> (SessionId, Option<String>) {
+        let collector = Collector::default().start();
+        let session_id = server
+            .send(Connect {
+                addr: collector.clone().recipient(),
+                channel,
+                remote: Some(remote.to_owned()),
+                initial_connect,
+                token,
+                restrictions: Restrictions::default(),
+                langs: Languages::default(),
+                asn: None,
+                as_org: None,
+            })
+            .await
+            .unwrap();
+        let token = collector
+            .send(Collected)
+            .await
+            .unwrap()
+            .first()
+            .and_then(|welcome| serde_json::from_str::<serde_json::Value>(welcome).ok())
+            .and_then(|welcome| welcome["token"].as_str().map(ToOwned::to_owned));
+        (session_id, token)
+    }
+
+    #[actix_rt::test]
+    async fn test_principal_tokens() {
+        let settings = Settings {
+            statsd_host: "".to_owned(),
+            max_channel_connections: 5,
+            ..Default::default()
+        };
+        let server = ChannelServer::new(&settings, &MozLogger::new_human()).start();
+        let channel = ChannelID::default();
+
+        let (auth, auth_token) = connect(&server, channel, true, "1.1.1.1", None).await;
+        assert_ne!(auth, 0);
+        let (supplicant, supplicant_token) =
+            connect(&server, channel, false, "2.2.2.2", None).await;
+        assert_ne!(supplicant, 0);
+        assert!(auth_token.is_some() && supplicant_token.is_some());
+        assert_ne!(auth_token, supplicant_token);
+
+        // Both principals are in, so the channel is locked, even to an IP
+        // that's already connected.
+        let (third, _) = connect(&server, channel, false, "1.1.1.1", None).await;
+        assert_eq!(third, 0);
+        let (forged, _) =
+            connect(&server, channel, false, "1.1.1.1", Some("bogus".to_owned())).await;
+        assert_eq!(forged, 0);
+
+        // A principal may rejoin from anywhere with its token, and keeps it.
+        let (rejoin, rejoin_token) =
+            connect(&server, channel, false, "3.3.3.3", auth_token.clone()).await;
+        assert_ne!(rejoin, 0);
+        assert_eq!(rejoin_token, auth_token);
+
+        // Tokens only work on the channel they were issued for.
+        let other = ChannelID::default();
+        let (other_auth, _) = connect(&server, other, true, "4.4.4.4", None).await;
+        assert_ne!(other_auth, 0);
+        let (stolen, _) = connect(&server, other, false, "1.1.1.1", auth_token.clone()).await;
+        assert_eq!(stolen, 0);
+
+        // Nor can one create a channel, and nothing is left behind for it.
+        let (created, _) =
+            connect(&server, ChannelID::default(), true, "5.5.5.5", auth_token).await;
+        assert_eq!(created, 0);
+        assert_eq!(server.send(ListChannels).await.unwrap().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_channel_restrictions() {
+        let settings = Settings {
+            statsd_host: "".to_owned(),
+            ip_disclosure: "truncate".to_owned(),
+            ..Default::default()
+        };
+        let server = ChannelServer::new(&settings, &MozLogger::new_human()).start();
+        let channel = ChannelID::default();
+        let creator = Collector::default().start();
+        let creator_id = server
+            .send(Connect {
+                addr: creator.clone().recipient(),
+                channel,
+                remote: Some("1.1.1.1".to_owned()),
+                initial_connect: true,
+                token: None,
+                restrictions: Restrictions {
+                    location: Granularity::Country,
+                    ..Default::default()
+                },
+                langs: Languages::parse("fr", "en"),
+                asn: Some(3320),
+                as_org: Some("Deutsche Telekom AG".to_owned()),
+            })
+            .await
+            .unwrap();
+        assert_ne!(creator_id, 0);
+        let (joiner, _) = connect(&server, channel, false, "2.2.2.2", None).await;
+        server
+            .send(ClientMessage {
+                id: joiner,
+                message_type: MessageType::Text,
+                msg: "hello".to_owned(),
+                channel,
+                sender: meta::SenderData {
+                    remote: Some("2.2.2.2".to_owned()),
+                    location: Location {
+                        city: Some("Berlin".into()),
+                        country: Some(Name::Localized(
+                            vec![
+                                ("de".to_owned(), "Deutschland".to_owned()),
+                                ("fr".to_owned(), "Allemagne".to_owned()),
+                            ]
+                            .into_iter()
+                            .collect(),
+                        )),
+                        ..Default::default()
+                    },
+                    country_code: Some("DE".to_owned()),
+                    region_code: Some("DE-BE".to_owned()),
+                    ..Default::default()
+                },
+            })
+            .await
+            .unwrap();
+        let messages = creator.send(Collected).await.unwrap();
+        let relayed: serde_json::Value = serde_json::from_str(&messages[1]).unwrap();
+        assert_eq!(
+            relayed,
+            json!({
+                "message": "hello",
+                "sender": {"remote": "2.2.2.0/24", "country": "Allemagne", "country_code": "DE"},
+            })
+        );
+    }
+
+    /// Relay `message` from `sender_id`, the same for every participant.
+    fn send(
+        server: &mut ChannelServer,
+        channel: &ChannelID,
+        message: &str,
+        sender_id: SessionId,
+    ) -> Result<(), perror::HandlerError> {
+        server.send_message(channel, sender_id, message.len(), |_| message.to_owned())
+    }
+
+    fn test_server(settings: Settings, channel: ChannelID, party_count: usize) -> ChannelServer {
+        let settings = Settings {
+            statsd_host: "".to_owned(),
+            ..settings
+        };
+        let mut server = ChannelServer::new(&settings, &MozLogger::new_human());
+        let mut group: Channels = HashMap::new();
+        for session_id in 1..=party_count {
+            group.insert(
+                session_id,
+                Channel {
+                    session_id,
+                    started: Instant::now(),
+                    sent: Usage::default(),
+                    remote: Some(format!("127.0.0.{}", session_id)),
+                    langs: Languages::default(),
+                },
+            );
+        }
+        server.channels.insert(channel, group);
+        server
+    }
+
+    #[test]
+    fn test_usage_limits() {
+        let limits = UsageLimits {
+            max_exchanges: 2,
+            max_data: 10,
+        };
+        let mut usage = Usage::default();
+        assert_eq!(usage.check(10, &limits), Ok(()));
+        assert_eq!(usage.check(11, &limits), Err(LimitExceeded::Data));
+        usage.record(4);
+        assert_eq!(usage.check(6, &limits), Ok(()));
+        assert_eq!(usage.check(7, &limits), Err(LimitExceeded::Data));
+        usage.record(6);
+        assert_eq!(usage.check(0, &limits), Err(LimitExceeded::Messages));
+
+        let unlimited = UsageLimits {
+            max_exchanges: 0,
+            max_data: 0,
+        };
+        let mut usage = Usage::default();
+        for _ in 0..300 {
+            usage.record(1000);
+        }
+        assert_eq!(usage.check(1000, &unlimited), Ok(()));
+        assert_eq!(usage.msg_count, 300);
+        assert_eq!(usage.data_exchanged, 300_000);
+    }
+
+    #[test]
+    fn test_exchanges_independent_of_fanout() {
+        let channel = ChannelID::default();
+        for party_count in 2..=5 {
+            let settings = Settings {
+                max_exchanges: 10,
+                max_channel_connections: 5,
+                ..Default::default()
+            };
+            let mut server = test_server(settings, channel, party_count);
+            for i in 0..10 {
+                // alternate senders, as a real exchange would.
+                let sender = i % 2 + 1;
+                assert!(send(&mut server, &channel, "hello", sender).is_ok());
+            }
+            assert_eq!(server.info[&channel].usage.msg_count, 10);
+            assert_eq!(server.info[&channel].usage.data_exchanged, 50);
+            assert_eq!(server.channels[&channel][&1].sent.msg_count, 5);
+            assert_eq!(server.channels[&channel][&2].sent.msg_count, 5);
+            for observer in 3..=party_count {
+                assert_eq!(server.channels[&channel][&observer].sent.msg_count, 0);
+            }
+            assert!(send(&mut server, &channel, "hello", 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_usage_counts_payload() {
+        let channel = ChannelID::default();
+        let mut server = test_server(Settings::default(), channel, 2);
+        // Only the client's payload counts, not the envelope peers are sent.
+        let render = |_: &Languages| json!({"message": "hello", "sender": {}}).to_string();
+        assert!(server.send_message(&channel, 1, 5, render).is_ok());
+        assert_eq!(server.info[&channel].usage.data_exchanged, 5);
+        assert_eq!(server.channels[&channel][&1].sent.data_exchanged, 5);
+    }
+
+    #[test]
+    fn test_channel_data_limit() {
+        let channel = ChannelID::default();
+        let settings = Settings {
+            max_exchanges: 0,
+            max_data: 10,
+            ..Default::default()
+        };
+        let mut server = test_server(settings, channel, 3);
+        assert!(send(&mut server, &channel, "12345", 1).is_ok());
+        assert!(send(&mut server, &channel, "12345", 2).is_ok());
+        assert!(send(&mut server, &channel, "1", 1).is_err());
+        // A rejected message is not counted.
+        assert_eq!(server.info[&channel].usage.data_exchanged, 10);
+    }
+
+    #[test]
+    fn test_sender_limits() {
+        let channel = ChannelID::default();
+        let settings = Settings {
+            max_exchanges: 0,
+            max_sender_exchanges: 2,
+            max_sender_data: 8,
+            ..Default::default()
+        };
+        let mut server = test_server(settings, channel, 2);
+        assert!(send(&mut server, &channel, "abc", 1).is_ok());
+        assert!(send(&mut server, &channel, "abc", 1).is_ok());
+        // The first sender is out of messages, the other direction is not.
+        assert!(send(&mut server, &channel, "abc", 1).is_err());
+        assert!(send(&mut server, &channel, "abcdefgh", 2).is_ok());
+        // ...but it is now out of data.
+        assert!(send(&mut server, &channel, "a", 2).is_err());
+        assert_eq!(server.info[&channel].usage.msg_count, 3);
+    }
+
+    #[test]
+    fn test_non_participant_ignored() {
+        let channel = ChannelID::default();
+        let settings = Settings {
+            max_exchanges: 1,
+            ..Default::default()
+        };
+        let mut server = test_server(settings, channel, 2);
+        assert!(send(&mut server, &channel, "hello", 99).is_ok());
+        assert!(send(&mut server, &channel, "hello", 1).is_ok());
+        assert!(send(&mut server, &channel, "hello", 2).is_err());
+    }
+
+    #[test]
+    fn test_close_all() {
+        let channel = ChannelID::default();
+        let mut server = test_server(Settings::default(), channel, 2);
+        server.channels.insert(ChannelID::default(), HashMap::new());
+        server.close_all(DisconnectReason::ServerShutdown);
+        assert!(server.channels.is_empty());
+        assert!(server.info.is_empty());
+        assert_eq!(
+            DisconnectReason::ServerShutdown.close_code(),
+            CloseCode::Away
         );
-        test_group.insert(
-            2,
+    }
+
+    #[test]
+    fn test_expire_channels() {
+        let settings = Settings {
+            conn_lifespan: 300,
+            ..Default::default()
+        };
+        let fresh = ChannelID::default();
+        let stale = ChannelID::default();
+        let mut server = test_server(settings, fresh, 2);
+        let now = Instant::now();
+        let mut group: Channels = HashMap::new();
+        group.insert(
+            3,
             Channel {
-                session_id: 1,
-                started: Instant::now(),
-                msg_count: 0,
-                data_exchanged: 0,
-                remote: Some("127.0.0.2".to_owned()),
+                session_id: 3,
+                started: now - Duration::from_secs(301),
+                sent: Usage::default(),
+                remote: None,
+                langs: Languages::default(),
             },
         );
-
-        assert!(reconnect_check(&test_group, &None, None) == false);
-        assert!(reconnect_check(&test_group, &Some("10.0.0.1".to_owned()), None) == false);
-        assert!(reconnect_check(&test_group, &Some("127.0.0.2".to_owned()), None) == true);
+        server.channels.insert(stale, group);
+        server.expire_channels(now);
+        assert!(server.channels.contains_key(&fresh));
+        assert!(!server.channels.contains_key(&stale));
+        server.expire_channels(now + Duration::from_secs(300));
+        assert!(server.channels.is_empty());
     }
 }
@@ -1,109 +1,166 @@
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
 use cadence::{Counted, StatsdClient};
 use ipnet::IpNet;
-use slog::{debug, error, info};
+use slog::{debug, error, info, warn};
 
 use actix::{
-    fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, ContextFutureSpawner, Handler,
-    Running, StreamHandler, WrapFuture,
+    fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner,
+    Handler, Running, StreamHandler, WrapFuture,
 };
+use actix_web::web;
 use actix_web_actors::ws;
 
+use crate::abuse;
+use crate::asn;
 use crate::channelid;
+use crate::geo;
+use crate::ipfilter;
 use crate::logging;
 use crate::meta;
 use crate::metrics;
+use crate::origin;
+use crate::privacy;
+use crate::ratelimit;
 use crate::server;
 use crate::settings;
-use crate::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
 
 pub struct WsChannelSessionState {
     pub log: logging::MozLogger,
     pub metrics: StatsdClient,
     pub settings: settings::Settings,
-    pub iploc: maxminddb::Reader<Vec<u8>>,
+    pub geo: Arc<dyn geo::GeoProvider>,
+    /// ASN database, if `asn_mmdb_loc` is set
+    pub asn: Option<asn::AsnProvider>,
+    /// what of a sender may be logged; shared with the channel server
+    pub privacy: privacy::DisclosurePolicy,
     pub trusted_proxy_list: Vec<IpNet>,
+    pub rate_limit_action: ratelimit::RateLimitAction,
+    pub origin_policy: origin::OriginPolicy,
 }
 
 impl std::fmt::Debug for WsChannelSessionState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f,
-            "WsChannelSessionState{{ log: {:?}, metrics: {:?}, settings: {:?}, iploc: ..., trusted_proxy_list: {:?}}}",
+            "WsChannelSessionState{{ log: {:?}, metrics: {:?}, settings: {:?}, geo: ..., asn: ..., privacy: ..., trusted_proxy_list: {:?}, rate_limit_action: {:?}, origin_policy: {:?}}}",
             self.log,
             self.metrics,
             self.settings,
             self.trusted_proxy_list,
+            self.rate_limit_action,
+            self.origin_policy,
         )
     }
 }
 
 impl WsChannelSessionState {
-    pub fn new(settings: &settings::Settings, log: &logging::MozLogger) -> Self {
-        let iploc = maxminddb::Reader::open_readfile(&settings.mmdb_loc).unwrap_or_else(|_| {
-            panic!(
-                "Could not find mmdb file at {:?}/{}",
-                std::env::current_dir().unwrap().as_path().to_string_lossy(),
-                settings.mmdb_loc
-            )
-        });
-
-        let mut trusted_list: Vec<IpNet> = Vec::new();
-        trusted_list.push("10.0.0.0/8".parse().unwrap());
-        trusted_list.push("172.16.0.0/12".parse().unwrap());
-        trusted_list.push("192.168.0.0/16".parse().unwrap());
-
-        if !settings.trusted_proxy_list.is_empty() {
-            for mut proxy in settings.trusted_proxy_list.split(',') {
-                proxy = proxy.trim();
-                if !proxy.is_empty() {
-                    let mut fixed = proxy.to_owned();
-                    if !proxy.contains('/') {
-                        fixed = format!("{}/32", proxy);
-                        debug!(log.log, "Fixing single address {}", fixed);
-                    }
-                    match fixed.parse::<ipnet::IpNet>() {
-                        Ok(addr) => trusted_list.push(addr),
-                        Err(err) => {
-                            error!(
-                                log.log,
-                                r#"Ignoring unparsable IP address "{} {:?}"#, proxy, err
-                            );
-                        }
-                    };
-                }
-            }
-        }
+    pub fn new(
+        settings: &settings::Settings,
+        log: &logging::MozLogger,
+        geo: Arc<dyn geo::GeoProvider>,
+        asn: Option<asn::AsnProvider>,
+        privacy: privacy::DisclosurePolicy,
+    ) -> Self {
         WsChannelSessionState {
             log: log.clone(),
             metrics: metrics::metrics_from_opts(&settings, &log).unwrap(),
             settings: settings.clone(),
-            trusted_proxy_list: trusted_list,
-            iploc,
+            trusted_proxy_list: trusted_proxies(settings, log),
+            rate_limit_action: rate_limit_action(settings, log),
+            origin_policy: origin::OriginPolicy::new(settings, log),
+            geo,
+            asn,
+            privacy,
         }
     }
+
+    /// Switch to newly loaded settings.
+    pub fn update(&mut self, settings: &settings::Settings) {
+        self.trusted_proxy_list = trusted_proxies(settings, &self.log);
+        self.rate_limit_action = rate_limit_action(settings, &self.log);
+        self.origin_policy = origin::OriginPolicy::new(settings, &self.log);
+        self.privacy = self.privacy.reconfigure(settings);
+        self.settings = settings.clone();
+    }
+}
+
+fn trusted_proxies(settings: &settings::Settings, log: &logging::MozLogger) -> Vec<IpNet> {
+    let mut trusted_list: Vec<IpNet> = Vec::new();
+    trusted_list.push("10.0.0.0/8".parse().unwrap());
+    trusted_list.push("172.16.0.0/12".parse().unwrap());
+    trusted_list.push("192.168.0.0/16".parse().unwrap());
+    trusted_list.extend(ipfilter::parse_ip_list(&settings.trusted_proxy_list, log));
+    trusted_list
+}
+
+fn rate_limit_action(
+    settings: &settings::Settings,
+    log: &logging::MozLogger,
+) -> ratelimit::RateLimitAction {
+    settings
+        .rate_limit_action
+        .parse::<ratelimit::RateLimitAction>()
+        .unwrap_or_else(|err| {
+            error!(log.log, "{}, using default", err);
+            ratelimit::RateLimitAction::default()
+        })
+}
+
+/// Lives on each worker, and applies new settings to the worker's
+/// `WsChannelSessionState`.
+pub struct StateUpdater {
+    pub state: web::Data<RwLock<WsChannelSessionState>>,
+}
+
+impl Actor for StateUpdater {
+    type Context = Context<Self>;
+}
+
+impl Handler<server::UpdateSettings> for StateUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::UpdateSettings, _ctx: &mut Self::Context) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.update(&msg.0);
+        debug!(state.log.log, "Worker settings updated");
+    }
 }
 
 pub struct WsChannelSession {
     /// unique session id
     pub id: usize,
-    /// Client must send ping at least once per CLIENT_TIMEOUT seconds,
+    /// Client must send ping at least once per `client_timeout`,
     /// otherwise we drop connection.
     pub hb: Instant,
-    // max channel lifespan
-    pub expiry: Duration,
+    /// how often to ping the client
+    pub heartbeat: Duration,
+    /// how long the client may go without answering a ping
+    pub client_timeout: Duration,
     /// joined channel
     pub channel: channelid::ChannelID,
     /// is the first time we're connecting?
     pub initial_connection: bool,
+    /// principal token presented to rejoin the channel
+    pub token: Option<String>,
+    /// sender details to keep from peers, if this session creates the channel
+    pub restrictions: privacy::Restrictions,
     /// peer name
     pub meta: meta::SenderData,
+    /// the peer's address, as much of it as may be logged
+    pub remote_ip: Option<String>,
     /// Address wrapper for Channel server
     pub addr: Addr<server::ChannelServer>,
     /// logging pointer
     pub log: logging::MozLogger,
     /// metrics reporting pointer
     pub metrics: cadence::StatsdClient,
+    /// messages and data per second allowed from this client
+    pub limiter: ratelimit::RateLimiter,
+    /// what to do when the client goes over its rate limit
+    pub rate_limit_action: ratelimit::RateLimitAction,
+    /// per IP abuse scores
+    pub abuse: abuse::AbuseLedger,
 }
 
 impl Actor for WsChannelSession {
@@ -129,10 +186,15 @@ impl Actor for WsChannelSession {
                 channel: self.channel,
                 initial_connect: self.initial_connection,
                 remote: meta.remote,
+                token: self.token.clone(),
+                restrictions: self.restrictions.clone(),
+                langs: meta.langs,
+                asn: meta.asn,
+                as_org: meta.as_org,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
-                let remote = &act.meta.remote;
+                let remote = &act.remote_ip;
                 match res {
                     Ok(session_id) => {
                         if session_id == 0 {
@@ -165,7 +227,7 @@ impl Actor for WsChannelSession {
             self.log.log,
             "Killing session";
             "session" => &self.id,
-            "remote_ip" => &self.meta.remote,
+            "remote_ip" => &self.remote_ip,
         );
         self.addr.do_send(server::Disconnect {
             channel: self.channel,
@@ -186,10 +248,20 @@ impl Handler<server::TextMessage> for WsChannelSession {
                     self.log.log,
                     "Closing session";
                     "session" => &self.id,
-                    "remote_ip" => &self.meta.remote,
+                    "remote_ip" => &self.remote_ip,
                 );
                 ctx.stop();
             }
+            server::MessageType::Close(reason) => {
+                debug!(
+                    self.log.log,
+                    "Closing session";
+                    "session" => &self.id,
+                    "remote_ip" => &self.remote_ip,
+                    "reason" => reason.to_string(),
+                );
+                close(reason, ctx);
+            }
             server::MessageType::Text => ctx.text(msg.1),
         }
     }
@@ -201,10 +273,13 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
         debug!(
             self.log.log,
             "Websocket Message: {:?}", msg;
-            "remote_ip" => &self.meta.remote
+            "remote_ip" => &self.remote_ip
         );
         let msg = match msg {
-            Err(_) => {
+            Err(err) => {
+                if let Some(infraction) = abuse::Infraction::from_ws_error(&err) {
+                    self.abuse.record(&self.meta.remote, infraction);
+                }
                 ctx.stop();
                 return;
             }
@@ -213,7 +288,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
         debug!(
             self.log.log,
             "WEBSOCKET MESSAGE: {:?}", msg;
-            "remote_ip" => &self.meta.remote
+            "remote_ip" => &self.remote_ip
         );
         match msg {
             ws::Message::Ping(msg) => {
@@ -225,19 +300,19 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
             }
             ws::Message::Text(text) => {
                 self.hb = Instant::now();
-                let m = text.trim();
-                self.addr.do_send(server::ClientMessage {
-                    id: self.id,
-                    message_type: server::MessageType::Text,
-                    msg: m.to_owned(),
-                    channel: self.channel,
-                    sender: self.meta.clone(),
-                })
+                let m = text.trim().to_owned();
+                let wait = self.limiter.delay_for(m.len(), self.hb);
+                if wait == Duration::from_secs(0) {
+                    self.limiter.consume(m.len());
+                    self.relay(m);
+                    return;
+                }
+                self.rate_limited(m, wait, ctx);
             }
             ws::Message::Binary(_) => info!(
                 self.log.log,
                 "Unexpected binary";
-                "remote_ip" => &self.meta.remote,
+                "remote_ip" => &self.remote_ip,
             ),
             ws::Message::Close(_) => {
                 self.addr.do_send(server::Disconnect {
@@ -249,11 +324,13 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
                     self.log.log,
                     "Shutting down session";
                     "session" => &self.id,
-                    "remote_ip" => &self.meta.remote,
+                    "remote_ip" => &self.remote_ip,
                 );
                 ctx.stop();
             }
             ws::Message::Continuation(_) => {
+                self.abuse
+                    .record(&self.meta.remote, abuse::Infraction::ProtocolError);
                 ctx.stop();
             }
             ws::Message::Nop => (),
@@ -262,21 +339,82 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
 }
 
 impl WsChannelSession {
-    /// helper method that sends ping to client every second.
+    /// Pass a client message on to the channel server.
+    fn relay(&self, msg: String) {
+        self.addr.do_send(server::ClientMessage {
+            id: self.id,
+            message_type: server::MessageType::Text,
+            msg,
+            channel: self.channel,
+            sender: self.meta.clone(),
+        })
+    }
+
+    /// Handle a message that arrived faster than the session's rate limits
+    /// allow, according to the configured `rate_limit_action`.
+    fn rate_limited(&mut self, msg: String, wait: Duration, ctx: &mut ws::WebsocketContext<Self>) {
+        let action = self.rate_limit_action.resolve(wait);
+        if let Some(infraction) = abuse::Infraction::from_rate_limit(action) {
+            self.abuse.record(&self.meta.remote, infraction);
+        }
+        match action {
+            ratelimit::RateLimitAction::Delay => {
+                debug!(
+                    self.log.log,
+                    "Rate limited, delaying message";
+                    "session" => &self.id,
+                    "remote_ip" => &self.remote_ip,
+                    "delay_ms" => wait.as_millis() as u64,
+                );
+                self.metrics.incr("conn.rate.delayed").ok();
+                self.limiter.consume(msg.len());
+                ctx.run_later(wait, move |act, _ctx| act.relay(msg));
+            }
+            ratelimit::RateLimitAction::Drop => {
+                info!(
+                    self.log.log,
+                    "Rate limited, dropping message";
+                    "session" => &self.id,
+                    "channel" => &self.channel.as_string(),
+                    "remote_ip" => &self.remote_ip,
+                );
+                self.metrics.incr("conn.rate.dropped").ok();
+            }
+            ratelimit::RateLimitAction::Disconnect => {
+                warn!(
+                    self.log.log,
+                    "Rate limited, disconnecting";
+                    "session" => &self.id,
+                    "channel" => &self.channel.as_string(),
+                    "remote_ip" => &self.remote_ip,
+                );
+                self.metrics.incr("conn.rate.disconnect").ok();
+                self.addr.do_send(server::Disconnect {
+                    id: self.id,
+                    channel: self.channel,
+                    reason: server::DisconnectReason::RateLimited,
+                });
+                close(server::DisconnectReason::RateLimited, ctx);
+            }
+        }
+    }
+
+    /// helper method that sends ping to client every `heartbeat`.
     ///
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.heartbeat, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
                 // heartbeat timed out
                 info!(
                     act.log.log,
-                    "Client connected too long";
+                    "Client heartbeat timed out. Disconnecting";
                     "session" => &act.id,
                     "channel" => &act.channel.as_string(),
-                    "remote_ip" => &act.meta.remote,
+                    "remote_ip" => &act.remote_ip,
                 );
+                act.metrics.incr("conn.timeout").ok();
 
                 // notify server
                 act.addr.do_send(server::Disconnect {
@@ -284,27 +422,9 @@ impl WsChannelSession {
                     channel: act.channel,
                     reason: server::DisconnectReason::Timeout,
                 });
-                act.metrics.incr("conn.expired").ok();
 
                 // stop actor
-                ctx.stop();
-                return;
-            }
-            if Instant::now().duration_since(act.hb) > act.expiry {
-                info!(
-                    act.log.log,
-                    "Client time-out. Disconnecting";
-                    "session" => &act.id,
-                    "channel" => &act.channel.as_string(),
-                    "remote_ip" => &act.meta.remote,
-                );
-                act.metrics.incr("conn.timeout").ok();
-                act.addr.do_send(server::Disconnect {
-                    id: act.id,
-                    channel: act.channel,
-                    reason: server::DisconnectReason::Timeout,
-                });
-                ctx.stop();
+                close(server::DisconnectReason::Timeout, ctx);
                 return;
             }
             // Send the ping.
@@ -312,3 +432,12 @@ impl WsChannelSession {
         });
     }
 }
+
+/// Send the client a close frame explaining why, and stop the session.
+fn close(reason: server::DisconnectReason, ctx: &mut ws::WebsocketContext<WsChannelSession>) {
+    ctx.close(Some(ws::CloseReason {
+        code: reason.close_code(),
+        description: Some(reason.to_string()),
+    }));
+    ctx.stop();
+}
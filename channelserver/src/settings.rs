@@ -1,31 +1,208 @@
 use std::env;
+use std::path::Path;
 
 use config::{Config, ConfigError, Environment, File};
+use docopt::ArgvMap;
 use serde::{Deserialize, Serialize};
 
+use crate::geo::{self, ProviderKind};
+use crate::geoheaders;
+use crate::ipfilter;
+use crate::links;
+use crate::listen;
+use crate::origin::{self, MissingOriginPolicy};
+use crate::privacy::{self, Granularity, IpDisclosure};
+use crate::ratelimit::RateLimitAction;
+use crate::tls;
+
 static PREFIX: &str = "PAIR";
 
+/// Settings that are only read at startup. Changing them on reload has no
+/// effect until the server is restarted.
+const RESTART_REQUIRED: &[&str] = &[
+    "hostname",
+    "port",
+    "listen",
+    "admin_listen",
+    "unix_socket_mode",
+    "statsd_host",
+    "human_logs",
+    "mmdb_loc",
+    "asn_mmdb_loc",
+    "geo_provider",
+    "geo_static_file",
+    "geo_headers",
+    "geo_reload",
+    "ip_allow_list",
+    "ip_deny_list",
+    "ip_filter_file",
+    "ip_filter_reload",
+    "abuse_threshold",
+    "abuse_half_life",
+    "abuse_ban_duration",
+    "pow_difficulty",
+    "pow_max_difficulty",
+    "pow_target_rate",
+    "pow_secret",
+    "pow_ttl",
+    "ip_hash_secret",
+    "tls_cert",
+    "tls_key",
+    "tls_min_version",
+    "tls_reload",
+];
+
+/// Command line options. Every `Settings` field can be set with the
+/// hyphenated form of its name, e.g. `--max-exchanges=20` for
+/// `max_exchanges`.
+pub const USAGE: &str = "
+Websocket channel server.
+
+Usage:
+    channelserver [options]
+    channelserver (-h | --help)
+    channelserver --check-config [options]
+    channelserver --version
+
+Settings are read from the built in defaults, then the config file, then
+PAIR_* environment variables, then these options, each overriding the last.
+
+Options:
+    -h, --help                          Show this message.
+    --version                           Show the version.
+    --config=<PATH>                     Config file to read, instead of config/$RUN_MODE.
+    --check-config                      Print the resolved configuration, and exit
+                                        non-zero if it is invalid.
+    --hostname=<HOST>                   Address to listen on (0.0.0.0).
+    --port=<PORT>                       Port to listen on (8000).
+    --listen=<LIST>                     Comma delimited endpoints to serve on, each host:port,
+                                        [ipv6]:port or unix:<path>. Replaces hostname and port.
+    --admin-listen=<LIST>               Endpoints to serve the admin and health routes on,
+                                        instead of alongside the websocket routes.
+    --unix-socket-mode=<MODE>           Octal permissions for unix sockets, e.g. 660.
+    --trust-unix-socket=<BOOL>          Believe X-Forwarded-For and geo headers from unix
+                                        socket peers; only if unix-socket-mode keeps out all
+                                        but the proxy (false).
+    --public-url=<URL>                  External base URL of the server, e.g.
+                                        wss://pair.example.com.
+    --deep-link-template=<TEMPLATE>     Extra link for clients, with {channelid}, {link}
+                                        and {url} filled in.
+    --max-channel-connections=<N>       Max connections per channel (3).
+    --conn-lifespan=<SECS>              Channel lifespan, 0 for none (300).
+    --client-timeout=<SECS>             Client timeout for pong responses (30).
+    --max-exchanges=<N>                 Max messages relayed through a channel (10).
+    --max-data=<OCTETS>                 Max octets relayed through a channel, 0 for unlimited (0).
+    --max-sender-exchanges=<N>          Max messages one participant may send (0).
+    --max-sender-data=<OCTETS>          Max octets one participant may send (0).
+    --max-msg-rate=<N>                  Max messages per second from one session (0).
+    --max-data-rate=<OCTETS>            Max octets per second from one session (0).
+    --rate-limit-action=<ACTION>        drop, delay or disconnect (drop).
+    --debug=<BOOL>                      In debug mode? (false).
+    --verbose=<BOOL>                    Verbose errors? (false).
+    --mmdb-loc=<PATH>                   MaxMind database path (mmdb/latest/GeoLite2-City.mmdb).
+    --asn-mmdb-loc=<PATH>               MaxMind ASN database path, if any.
+    --geo-provider=<LIST>               Geolocation providers to try in order: maxmind,
+                                        header, static or none (maxmind, header).
+    --geo-static-file=<PATH>            Table of locations for the static provider.
+    --geo-headers=<LIST>                CDN geo header mappings for the header provider (gcp).
+    --geo-reload=<SECS>                 Seconds between checks of the MaxMind databases (60).
+    --forward-raw-ua=<BOOL>             Send peers the raw User-Agent string (false).
+    --withhold-fields=<LIST>            Comma delimited sender fields never sent to peers.
+    --ip-disclosure=<HOW>               Sender IP sent to peers: full, truncate, hash or
+                                        none (full).
+    --ip-hash-secret=<SECRET>           Key for hashed sender IPs.
+    --location-granularity=<LEVEL>      Most detailed sender location sent to peers: city,
+                                        region, country or none (city).
+    --statsd-host=<HOST>                Metric statsd host (localhost:8125).
+    --trusted-proxy-list=<LIST>         Comma delimited list of proxy hosts.
+    --ip-allow-list=<LIST>              Comma delimited CIDR ranges always admitted.
+    --ip-deny-list=<LIST>               Comma delimited CIDR ranges refused.
+    --ip-filter-file=<PATH>             File of allow/deny CIDR rules, reloaded on change.
+    --ip-filter-reload=<SECS>           Seconds between checks of the IP filter file (10).
+    --allowed-origins=<LIST>            Comma delimited Origins allowed to connect.
+    --missing-origin=<POLICY>           Requests without an Origin: allow or deny (allow).
+    --abuse-threshold=<POINTS>          Abuse score that triggers a temporary ban, 0 for none (0).
+    --abuse-half-life=<SECS>            Seconds for an abuse score to decay by half (300).
+    --abuse-ban-duration=<SECS>         Seconds a temporary abuse ban lasts (600).
+    --pow-difficulty=<BITS>             Base proof-of-work bits to create a channel (0).
+    --pow-max-difficulty=<BITS>         Most proof-of-work bits ever required (24).
+    --pow-target-rate=<N>               Channels per minute before the difficulty rises (0).
+    --pow-secret=<SECRET>               Proof-of-work challenge signing key.
+    --pow-ttl=<SECS>                    Seconds a proof-of-work challenge is valid (120).
+    --ip-reputation-server=<URL>        IP reputation server.
+    --iprep-min=<N>                     Minimum IP reputation (0).
+    --ip-violation=<NAME>               Name of the abuse violation (channel_abuse).
+    --heartbeat=<SECS>                  Heartbeat rate in seconds for pings (5).
+    --shutdown-grace=<SECS>             Seconds channels may finish in after SIGTERM (30).
+    --tls-cert=<PATH>                   PEM certificate chain. Serve TLS if set.
+    --tls-key=<PATH>                    PEM private key for the certificate.
+    --tls-min-version=<VERSION>         Lowest TLS version accepted, 1.2 or 1.3 (1.2).
+    --tls-reload=<SECS>                 Seconds between checks of the TLS files for changes (60).
+    --human-logs=<BOOL>                 Show human readable logs (false).
+    --default-lang=<LANG>               Default language if none presented (en).
+";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct Settings {
     pub hostname: String,             // server hostname (localhost)
     pub port: u16,                    // server port (8000)
+    pub listen: String,               // host:port, [v6]:port or unix:path list ("" ; hostname:port)
+    pub admin_listen: String,         // Endpoints for admin/health routes ("" ; same as listen)
+    pub unix_socket_mode: String,     // Octal permissions for unix sockets ("" ; umask)
+    pub trust_unix_socket: bool,      // Treat unix socket peers as trusted proxies (false)
+    pub public_url: String,           // External base URL, for absolute channel URLs ("")
+    pub deep_link_template: String,   // Extra link for clients, e.g. a pairing page ("")
     pub max_channel_connections: u8,  // Max connections per channel (10)
-    pub conn_lifespan: u64,           // Total connection lifespan in seconds (300)
+    pub conn_lifespan: u64,           // Channel lifespan in seconds, 0 for none (300)
     pub client_timeout: u64,          // Client timeout for pong responses (30)
-    pub max_exchanges: u8,            // Max number of messages before channel shutdown (3)
-    pub max_data: u64,                // Max amount of data octets to exchange (0 ; unlimited)
+    pub max_exchanges: u8,            // Max messages relayed through a channel (10)
+    pub max_data: u64,                // Max data octets relayed through a channel (0 ; unlimited)
+    pub max_sender_exchanges: u8,     // Max messages one participant may send (0 ; unlimited)
+    pub max_sender_data: u64,         // Max data octets one participant may send (0 ; unlimited)
+    pub max_msg_rate: u64,            // Max messages per second from one session (0 ; unlimited)
+    pub max_data_rate: u64,           // Max data octets per second from one session (0 ; unlimited)
+    pub rate_limit_action: String,    // Over-rate handling: drop, delay, disconnect ("drop")
     pub debug: bool,                  // In debug mode? (false)
     pub verbose: bool,                // Verbose Errors? (false)
     pub mmdb_loc: String,             // MaxMind database path ("mmdb/latest/GeoLite2-City.mmdb")
+    pub asn_mmdb_loc: String,         // MaxMind ASN database path ("" ; no ASN lookups)
+    pub geo_provider: String,         // Geolocation providers to try in order ("maxmind, header")
+    pub geo_static_file: String,      // Table of locations for the "static" provider ("")
+    pub geo_headers: String,          // CDN geo header mappings for the "header" provider ("gcp")
+    pub geo_reload: u64,              // Seconds between checks of the databases for changes (60)
+    pub forward_raw_ua: bool,         // Send peers the raw User-Agent, not only its parts (false)
+    pub withhold_fields: String,      // comma delimited sender fields never sent to peers ("")
+    pub ip_disclosure: String,        // Sender IP sent: full, truncate, hash, none ("full")
+    pub ip_hash_secret: String,       // Key for hashed sender IPs ("" ; random)
+    pub location_granularity: String, // Most detailed location sent: city - none ("city")
     pub statsd_host: String,          // Metric statsd host (localhost)
     pub trusted_proxy_list: String,   // comma delimited list of proxy hosts ("")
+    pub ip_allow_list: String,        // comma delimited CIDR ranges always admitted ("")
+    pub ip_deny_list: String,         // comma delimited CIDR ranges refused ("")
+    pub ip_filter_file: String,       // File of "allow"/"deny" CIDR rules, reloaded on change ("")
+    pub ip_filter_reload: u64,        // Seconds between checks of ip_filter_file for changes (10)
+    pub allowed_origins: String, // comma delimited Origins allowed to connect; blank for any ("")
+    pub missing_origin: String,  // Requests without an Origin: "allow" or "deny" ("allow")
+    pub abuse_threshold: u64,    // Abuse score that triggers a temporary ban (0 ; disabled)
+    pub abuse_half_life: u64,    // Seconds for an abuse score to decay by half (300)
+    pub abuse_ban_duration: u64, // Seconds a temporary abuse ban lasts (600)
+    pub pow_difficulty: u8,      // Base proof-of-work bits to create a channel (0 ; none)
+    pub pow_max_difficulty: u8,  // Most proof-of-work bits ever required (24)
+    pub pow_target_rate: u64,    // Channels/minute before PoW difficulty rises (0 ; fixed)
+    pub pow_secret: String,      // Proof-of-work challenge signing key ("" ; random)
+    pub pow_ttl: u64,            // Seconds a proof-of-work challenge is valid (120)
     pub ip_reputation_server: String, // IP Reputation server. Leave blank to disable ("")
-    pub iprep_min: u8,                // Minimum IP Reputation (0)
-    pub ip_violation: String,         // Name of the abuse violation
-    pub heartbeat: u64,               // Heartbeat rate in seconds for pings (5)
-    pub human_logs: bool,             // Show "Human readable" logs (false)
-    pub default_lang: String,         // Default language if none presented? (None)
+    pub iprep_min: u8,           // Minimum IP Reputation (0)
+    pub ip_violation: String,    // Name of the abuse violation
+    pub heartbeat: u64,          // Heartbeat rate in seconds for pings (5)
+    pub shutdown_grace: u64,     // Seconds channels may finish in after SIGTERM (30)
+    pub tls_cert: String,        // PEM certificate chain; serve TLS if set ("" ; plain HTTP)
+    pub tls_key: String,         // PEM private key for tls_cert ("")
+    pub tls_min_version: String, // Lowest TLS version accepted: 1.2 or 1.3 ("1.2")
+    pub tls_reload: u64,         // Seconds between checks of the TLS files for changes (60)
+    pub human_logs: bool,        // Show "Human readable" logs (false)
+    pub default_lang: String,    // Default language if none presented? (None)
 }
 
 impl Default for Settings {
@@ -33,20 +210,60 @@ impl Default for Settings {
         Self {
             hostname: "0.0.0.0".to_owned(),
             port: 8000,
+            listen: "".to_owned(),
+            admin_listen: "".to_owned(),
+            unix_socket_mode: "".to_owned(),
+            trust_unix_socket: false,
+            public_url: "".to_owned(),
+            deep_link_template: "".to_owned(),
             max_channel_connections: 3,
             conn_lifespan: 300,
             client_timeout: 30,
             max_exchanges: 10,
             max_data: 0,
+            max_sender_exchanges: 0,
+            max_sender_data: 0,
+            max_msg_rate: 0,
+            max_data_rate: 0,
+            rate_limit_action: "drop".to_owned(),
             debug: false,
             verbose: false,
             mmdb_loc: "mmdb/latest/GeoLite2-City.mmdb".to_owned(),
+            asn_mmdb_loc: "".to_owned(),
+            geo_provider: "maxmind, header".to_owned(),
+            geo_static_file: "".to_owned(),
+            geo_headers: "gcp".to_owned(),
+            geo_reload: 60,
+            forward_raw_ua: false,
+            withhold_fields: "".to_owned(),
+            ip_disclosure: "full".to_owned(),
+            ip_hash_secret: "".to_owned(),
+            location_granularity: "city".to_owned(),
             statsd_host: "localhost:8125".to_owned(),
             trusted_proxy_list: "".to_owned(),
+            ip_allow_list: "".to_owned(),
+            ip_deny_list: "".to_owned(),
+            ip_filter_file: "".to_owned(),
+            ip_filter_reload: 10,
+            allowed_origins: "".to_owned(),
+            missing_origin: "allow".to_owned(),
+            abuse_threshold: 0,
+            abuse_half_life: 300,
+            abuse_ban_duration: 600,
+            pow_difficulty: 0,
+            pow_max_difficulty: 24,
+            pow_target_rate: 0,
+            pow_secret: "".to_owned(),
+            pow_ttl: 120,
             ip_reputation_server: "".to_owned(),
             iprep_min: 0,
             ip_violation: "channel_abuse".to_owned(),
             heartbeat: 5,
+            shutdown_grace: 30,
+            tls_cert: "".to_owned(),
+            tls_key: "".to_owned(),
+            tls_min_version: "1.2".to_owned(),
+            tls_reload: 60,
             human_logs: false,
             default_lang: "en".to_owned(),
         }
@@ -54,15 +271,356 @@ impl Default for Settings {
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
+    /// Resolve the settings. Later sources override earlier ones: the
+    /// defaults, then the config file, then the environment, then `args`.
+    pub fn new(args: &ArgvMap) -> Result<Self, ConfigError> {
         let mut settings = Config::new();
 
-        // Get the run environment
-        let env = env::var("RUN_MODE").unwrap_or_else(|_| "development".to_owned());
-        // start with any local config file.
-        settings
-            .merge(File::with_name(&format!("config/{}", env)).required(false))?
-            .merge(Environment::with_prefix(PREFIX))?;
+        // An explicitly requested config file has to exist.
+        let config = args.get_str("--config");
+        if config.is_empty() {
+            // Get the run environment
+            let env = env::var("RUN_MODE").unwrap_or_else(|_| "development".to_owned());
+            // start with any local config file.
+            settings.merge(File::with_name(&format!("config/{}", env)).required(false))?;
+        } else {
+            settings.merge(File::with_name(config))?;
+        }
+        settings.merge(Environment::with_prefix(PREFIX))?;
+        for name in Self::names() {
+            let value = args.get_str(&format!("--{}", name.replace('_', "-")));
+            if !value.is_empty() {
+                settings.set(&name, value)?;
+            }
+        }
         settings.try_into()
     }
+
+    /// Check the settings for values that would keep the server from running
+    /// as intended. Returns a description of every problem found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        for (name, list) in &[
+            ("trusted_proxy_list", &self.trusted_proxy_list),
+            ("ip_allow_list", &self.ip_allow_list),
+            ("ip_deny_list", &self.ip_deny_list),
+        ] {
+            for problem in ipfilter::check_ip_list(list) {
+                problems.push(format!("{}: {}", name, problem));
+            }
+        }
+        for problem in origin::check_origins(&self.allowed_origins) {
+            problems.push(format!("allowed_origins: {}", problem));
+        }
+        if let Err(err) = self.missing_origin.parse::<MissingOriginPolicy>() {
+            problems.push(format!("missing_origin: {}", err));
+        }
+        if let Err(err) = self.rate_limit_action.parse::<RateLimitAction>() {
+            problems.push(format!("rate_limit_action: {}", err));
+        }
+        for (name, list) in &[
+            ("listen", &self.listen),
+            ("admin_listen", &self.admin_listen),
+        ] {
+            if let Err(err) = listen::parse_endpoints(list) {
+                problems.push(format!("{}: {}", name, err));
+            }
+        }
+        if !self.public_url.is_empty() {
+            if let Err(err) = links::ws_base(&self.public_url) {
+                problems.push(format!("public_url: {}", err));
+            }
+        }
+        if !self.deep_link_template.is_empty() {
+            if let Err(err) = links::check_template(&self.deep_link_template, &self.public_url) {
+                problems.push(format!("deep_link_template: {}", err));
+            }
+        }
+        if let Err(err) = privacy::parse_fields(&self.withhold_fields) {
+            problems.push(format!("withhold_fields: {}", err));
+        }
+        if let Err(err) = self.ip_disclosure.parse::<IpDisclosure>() {
+            problems.push(format!("ip_disclosure: {}", err));
+        }
+        if let Err(err) = self.location_granularity.parse::<Granularity>() {
+            problems.push(format!("location_granularity: {}", err));
+        }
+        if let Err(err) = listen::parse_mode(&self.unix_socket_mode) {
+            problems.push(format!("unix_socket_mode: {}", err));
+        }
+        if self.max_channel_connections < 2 {
+            problems.push(format!(
+                "max_channel_connections: must be at least 2 for a channel to be paired, not {}",
+                self.max_channel_connections
+            ));
+        }
+        if self.heartbeat == 0 {
+            problems.push("heartbeat: must be at least 1 second".to_owned());
+        }
+        if self.client_timeout <= self.heartbeat {
+            problems.push(format!(
+                "client_timeout: must be longer than heartbeat ({}s), not {}s",
+                self.heartbeat, self.client_timeout
+            ));
+        }
+        if let Some(pos) = self.statsd_host.rfind(':') {
+            let port = &self.statsd_host[pos + 1..];
+            if port.parse::<u16>().is_err() {
+                problems.push(format!("statsd_host: {:?} is not a valid port", port));
+            }
+        }
+        if self.pow_difficulty > self.pow_max_difficulty {
+            problems.push(format!(
+                "pow_difficulty: {} is more than pow_max_difficulty ({})",
+                self.pow_difficulty, self.pow_max_difficulty
+            ));
+        }
+        if self.pow_max_difficulty > 64 {
+            problems.push(format!(
+                "pow_max_difficulty: {} bits can't reasonably be solved",
+                self.pow_max_difficulty
+            ));
+        }
+        if self.pow_ttl == 0 {
+            problems.push("pow_ttl: must be at least 1 second".to_owned());
+        }
+        if !self.asn_mmdb_loc.is_empty() && !Path::new(&self.asn_mmdb_loc).is_file() {
+            problems.push(format!("asn_mmdb_loc: cannot find {:?}", self.asn_mmdb_loc));
+        }
+        match geo::parse_providers(&self.geo_provider) {
+            Ok(kinds) => {
+                if kinds.contains(&ProviderKind::MaxMind) && !Path::new(&self.mmdb_loc).is_file() {
+                    problems.push(format!("mmdb_loc: cannot find {:?}", self.mmdb_loc));
+                }
+                if kinds.contains(&ProviderKind::Header) {
+                    if let Err(err) = geoheaders::parse_mappings(&self.geo_headers) {
+                        problems.push(format!("geo_headers: {}", err));
+                    }
+                }
+                if kinds.contains(&ProviderKind::Static) {
+                    if let Err(err) = geo::StaticProvider::open(&self.geo_static_file) {
+                        problems.push(format!("geo_static_file: {}", err));
+                    }
+                }
+            }
+            Err(err) => problems.push(format!("geo_provider: {}", err)),
+        }
+        if !self.ip_filter_file.is_empty() && !Path::new(&self.ip_filter_file).is_file() {
+            problems.push(format!(
+                "ip_filter_file: cannot find {:?}",
+                self.ip_filter_file
+            ));
+        }
+        if !self.tls_cert.is_empty() || !self.tls_key.is_empty() {
+            for (name, path) in &[("tls_cert", &self.tls_cert), ("tls_key", &self.tls_key)] {
+                if !Path::new(path).is_file() {
+                    problems.push(format!("{}: cannot find {:?}", name, path));
+                }
+            }
+        }
+        if let Err(err) = tls::parse_version(&self.tls_min_version) {
+            problems.push(format!("tls_min_version: {}", err));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Take the reloadable values from `reloaded`, keeping the current value
+    /// of anything that requires a restart. Returns the merged settings and
+    /// the names of the settings whose changes were refused.
+    pub fn merge_reload(&self, reloaded: Self) -> (Self, Vec<&'static str>) {
+        let (current, mut merged) =
+            match (serde_json::to_value(self), serde_json::to_value(&reloaded)) {
+                (Ok(serde_json::Value::Object(current)), Ok(serde_json::Value::Object(merged))) => {
+                    (current, merged)
+                }
+                _ => return (reloaded, Vec::new()),
+            };
+        let mut refused = Vec::new();
+        for name in RESTART_REQUIRED {
+            if current.get(*name) != merged.get(*name) {
+                if let Some(value) = current.get(*name) {
+                    merged.insert((*name).to_owned(), value.clone());
+                }
+                refused.push(*name);
+            }
+        }
+        match serde_json::from_value(serde_json::Value::Object(merged)) {
+            Ok(merged) => (merged, refused),
+            Err(_) => (self.clone(), RESTART_REQUIRED.to_vec()),
+        }
+    }
+
+    /// A copy of the settings that's safe to print.
+    pub fn redacted(&self) -> Self {
+        let mut settings = self.clone();
+        if !settings.pow_secret.is_empty() {
+            settings.pow_secret = "[REDACTED]".to_owned();
+        }
+        if !settings.ip_hash_secret.is_empty() {
+            settings.ip_hash_secret = "[REDACTED]".to_owned();
+        }
+        settings
+    }
+
+    /// The names of all the settings.
+    fn names() -> Vec<String> {
+        match serde_json::to_value(Self::default()) {
+            Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use docopt::Docopt;
+
+    fn args(argv: &[&str]) -> ArgvMap {
+        Docopt::new(USAGE)
+            .and_then(|d| {
+                d.argv(std::iter::once("channelserver").chain(argv.iter().cloned()))
+                    .parse()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_every_setting_has_an_option() {
+        for name in Settings::names() {
+            let option = format!("--{}=", name.replace('_', "-"));
+            assert!(USAGE.contains(&option), "No option for {}", name);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let settings = Settings {
+            mmdb_loc: "src/settings.rs".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+
+        // No database is needed unless the MaxMind provider is used.
+        let settings = Settings {
+            mmdb_loc: "missing.mmdb".to_owned(),
+            geo_provider: "header".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        let settings = Settings {
+            geo_provider: "header, static, gps".to_owned(),
+            ..Default::default()
+        };
+        let problems = settings.validate().unwrap_err();
+        assert_eq!(
+            problems,
+            vec!["geo_provider: Unknown geolocation provider: \"gps\""]
+        );
+        let settings = Settings {
+            geo_provider: "header".to_owned(),
+            geo_headers: "gcp, akamai".to_owned(),
+            ..Default::default()
+        };
+        let problems = settings.validate().unwrap_err();
+        assert_eq!(
+            problems,
+            vec!["geo_headers: Unknown geo header preset: \"akamai\""]
+        );
+        let settings = Settings {
+            tls_min_version: "1.1".to_owned(),
+            mmdb_loc: "src/settings.rs".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            Err(vec!["tls_min_version: TLS 1.1 is not supported".to_owned()])
+        );
+
+        let settings = Settings {
+            mmdb_loc: "missing.mmdb".to_owned(),
+            trusted_proxy_list: "10.0.0.1, proxy.example.com".to_owned(),
+            max_channel_connections: 1,
+            statsd_host: "localhost:statsd".to_owned(),
+            rate_limit_action: "explode".to_owned(),
+            ip_disclosure: "partial".to_owned(),
+            withhold_fields: "ua, shoe_size".to_owned(),
+            asn_mmdb_loc: "missing-asn.mmdb".to_owned(),
+            ..Default::default()
+        };
+        let problems = settings.validate().unwrap_err();
+        assert_eq!(problems.len(), 8);
+        assert!(problems[0].starts_with("trusted_proxy_list: \"proxy.example.com\""));
+    }
+
+    #[test]
+    fn test_merge_reload() {
+        let current = Settings::default();
+        let reloaded = Settings {
+            port: 9000,
+            max_exchanges: 20,
+            trusted_proxy_list: "1.2.3.4".to_owned(),
+            pow_secret: "new".to_owned(),
+            ..Default::default()
+        };
+        let (merged, refused) = current.merge_reload(reloaded);
+        assert_eq!(refused, vec!["port", "pow_secret"]);
+        assert_eq!(merged.port, 8000);
+        assert_eq!(merged.pow_secret, "");
+        assert_eq!(merged.max_exchanges, 20);
+        assert_eq!(merged.trusted_proxy_list, "1.2.3.4");
+
+        let (_, refused) = current.merge_reload(Settings::default());
+        assert!(refused.is_empty());
+    }
+
+    #[test]
+    fn test_redacted() {
+        let settings = Settings {
+            pow_secret: "hunter2".to_owned(),
+            ..Default::default()
+        };
+        assert!(!serde_json::to_string(&settings.redacted())
+            .unwrap()
+            .contains("hunter2"));
+        assert_eq!(settings.pow_secret, "hunter2");
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = env::temp_dir().join(format!("settings-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"port": 9000, "max_exchanges": 20, "human_logs": true}"#,
+        )
+        .unwrap();
+        let config = path.to_string_lossy().into_owned();
+
+        let settings = Settings::new(&args(&["--config", &config])).unwrap();
+        assert_eq!(settings.port, 9000);
+        assert_eq!(settings.max_exchanges, 20);
+        assert!(settings.human_logs);
+        assert_eq!(settings.conn_lifespan, 300);
+
+        let settings = Settings::new(&args(&[
+            &format!("--config={}", config),
+            "--port=9001",
+            "--human-logs=false",
+            "--allowed-origins=https://example.com",
+        ]))
+        .unwrap();
+        assert_eq!(settings.port, 9001);
+        assert_eq!(settings.max_exchanges, 20);
+        assert!(!settings.human_logs);
+        assert_eq!(settings.allowed_origins, "https://example.com");
+
+        assert!(Settings::new(&args(&["--port=many"])).is_err());
+        std::fs::remove_file(&path).ok();
+        assert!(Settings::new(&args(&["--config", &config])).is_err());
+    }
 }
@@ -0,0 +1,283 @@
+//! Native TLS serving.
+//!
+//! The certificate chain and key are read from `tls_cert` and `tls_key`.
+//! Connections are handed the current certificate as soon as their client
+//! hello arrives, whether or not it names a server (SNI), so a renewed
+//! certificate can be swapped in without a restart: the files are
+//! checked for changes every `tls_reload` seconds and on `SIGHUP`. If the new
+//! files can't be loaded, the old certificate stays in use.
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use openssl::ssl::{
+    ClientHelloResponse, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+    SslVersion,
+};
+use slog::{error, info};
+
+use crate::error::{HandlerError, HandlerErrorKind};
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// Parse a `tls_min_version` value. Versions before 1.2 are refused: the
+/// Mozilla intermediate configuration the acceptor starts from disables them,
+/// and none of its ciphers work with them.
+pub fn parse_version(version: &str) -> Result<SslVersion, String> {
+    match version.trim().to_lowercase().trim_start_matches("tlsv") {
+        "1.0" | "1" | "1.1" => Err(format!("TLS {} is not supported", version.trim())),
+        "1.2" => Ok(SslVersion::TLS1_2),
+        "1.3" => Ok(SslVersion::TLS1_3),
+        _ => Err(format!("Unknown TLS version: {:?}", version)),
+    }
+}
+
+fn tls_err<E: std::fmt::Debug>(err: E) -> HandlerError {
+    HandlerErrorKind::TlsError(format!("{:?}", err)).into()
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(path))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Shared, swappable TLS certificate. Clones refer to the same certificate.
+#[derive(Clone)]
+pub struct TlsCertificate {
+    cert: String,
+    key: String,
+    min_version: SslVersion,
+    context: Arc<RwLock<SslContext>>,
+    modified: Arc<RwLock<(Option<SystemTime>, Option<SystemTime>)>>,
+    log: MozLogger,
+}
+
+impl std::fmt::Debug for TlsCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "TlsCertificate{{ cert: {:?}, key: {:?}, min_version: {:?} }}",
+            self.cert, self.key, self.min_version
+        )
+    }
+}
+
+impl TlsCertificate {
+    /// Load the certificate, if TLS is configured.
+    pub fn new(settings: &Settings, log: &MozLogger) -> Result<Option<Self>, HandlerError> {
+        if settings.tls_cert.is_empty() {
+            return Ok(None);
+        }
+        let min_version = parse_version(&settings.tls_min_version)
+            .map_err(|err| HandlerError::from(HandlerErrorKind::TlsError(err)))?;
+        let modified = (
+            modified_time(&settings.tls_cert),
+            modified_time(&settings.tls_key),
+        );
+        let context = acceptor_builder(&settings.tls_cert, &settings.tls_key, min_version)?
+            .build()
+            .into_context();
+        Ok(Some(Self {
+            cert: settings.tls_cert.clone(),
+            key: settings.tls_key.clone(),
+            min_version,
+            context: Arc::new(RwLock::new(context)),
+            modified: Arc::new(RwLock::new(modified)),
+            log: log.clone(),
+        }))
+    }
+
+    /// An acceptor that serves whatever certificate is current at the time
+    /// of each handshake. Clients that send no SNI get it too.
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, HandlerError> {
+        let mut builder = acceptor_builder(&self.cert, &self.key, self.min_version)?;
+        let context = self.context.clone();
+        builder.set_client_hello_callback(move |ssl, _alert| {
+            let context = context.read().unwrap_or_else(|e| e.into_inner());
+            ssl.set_ssl_context(&context)?;
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+        Ok(builder)
+    }
+
+    /// Re-read the certificate and key if either has been modified since
+    /// they were last read. Returns true if the certificate was replaced.
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = (modified_time(&self.cert), modified_time(&self.key));
+        {
+            let mut last = self.modified.write().unwrap_or_else(|e| e.into_inner());
+            if *last == modified {
+                return false;
+            }
+            *last = modified;
+        }
+        self.reload()
+    }
+
+    /// Re-read the certificate and key. The current certificate is kept if
+    /// they can't be loaded.
+    pub fn reload(&self) -> bool {
+        match acceptor_builder(&self.cert, &self.key, self.min_version) {
+            Ok(builder) => {
+                *self.context.write().unwrap_or_else(|e| e.into_inner()) =
+                    builder.build().into_context();
+                info!(self.log.log, "Loaded TLS certificate"; "cert" => &self.cert);
+                true
+            }
+            Err(err) => {
+                error!(
+                    self.log.log,
+                    "Could not reload TLS certificate, keeping the current one: {}", err;
+                    "cert" => &self.cert,
+                );
+                false
+            }
+        }
+    }
+
+    /// Check the certificate files for changes every `interval`.
+    pub fn watch(&self, interval: Duration) {
+        if interval == Duration::from_secs(0) {
+            return;
+        }
+        let cert = self.clone();
+        actix_rt::spawn(async move {
+            let mut ticker = actix_rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                cert.reload_if_changed();
+            }
+        });
+    }
+}
+
+fn acceptor_builder(
+    cert: &str,
+    key: &str,
+    min_version: SslVersion,
+) -> Result<SslAcceptorBuilder, HandlerError> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(tls_err)?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .map_err(tls_err)?;
+    builder.set_certificate_chain_file(cert).map_err(tls_err)?;
+    builder.check_private_key().map_err(tls_err)?;
+    builder
+        .set_min_proto_version(Some(min_version))
+        .map_err(tls_err)?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        ssl::{SslConnector, SslVerifyMode},
+        x509::X509NameBuilder,
+        x509::X509,
+    };
+
+    /// Write a self signed certificate and its key, returning their PEMs.
+    fn write_cert(cert_path: &Path, key_path: &Path, name: &str) -> Vec<u8> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let pem = cert.build().to_pem().unwrap();
+        fs::write(cert_path, &pem).unwrap();
+        fs::write(key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        pem
+    }
+
+    /// Handshake with an acceptor without sending SNI, returning the PEM of
+    /// the certificate it served.
+    fn served_pem(acceptor: &SslAcceptor) -> Vec<u8> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let acceptor = acceptor.clone();
+        let server = std::thread::spawn(move || acceptor.accept(server).map(|_| ()).is_ok());
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector
+            .build()
+            .configure()
+            .unwrap()
+            .use_server_name_indication(false)
+            .verify_hostname(false)
+            .connect("", client)
+            .unwrap();
+        let pem = stream.ssl().peer_certificate().unwrap().to_pem().unwrap();
+        drop(stream);
+        assert!(server.join().unwrap());
+        pem
+    }
+
+    fn current_pem(cert: &TlsCertificate) -> Vec<u8> {
+        let context = cert.context.read().unwrap();
+        context.certificate().unwrap().to_pem().unwrap()
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.2"), Ok(SslVersion::TLS1_2));
+        assert_eq!(parse_version("TLSv1.3"), Ok(SslVersion::TLS1_3));
+        assert!(parse_version("1.4").is_err());
+        assert!(parse_version("1.0").is_err());
+        assert!(parse_version("TLSv1.1").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let log = MozLogger::new_human();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("tls-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("tls-{}.key", std::process::id()));
+        let first = write_cert(&cert_path, &key_path, "first.example.com");
+        let settings = Settings {
+            tls_cert: cert_path.to_string_lossy().into_owned(),
+            tls_key: key_path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        assert!(TlsCertificate::new(&Settings::default(), &log)
+            .unwrap()
+            .is_none());
+        let cert = TlsCertificate::new(&settings, &log).unwrap().unwrap();
+        let acceptor = cert.acceptor().unwrap().build();
+        assert_eq!(current_pem(&cert), first);
+        assert_eq!(served_pem(&acceptor), first);
+        assert!(!cert.reload_if_changed());
+
+        let second = write_cert(&cert_path, &key_path, "second.example.com");
+        // Make sure the modification time moves even on coarse filesystems.
+        *cert.modified.write().unwrap() = (None, None);
+        assert!(cert.reload_if_changed());
+        assert_eq!(current_pem(&cert), second);
+        // Already built acceptors serve it, even to clients without SNI.
+        assert_eq!(served_pem(&acceptor), second);
+
+        // A broken certificate leaves the current one in place.
+        fs::write(&cert_path, "garbage").unwrap();
+        assert!(!cert.reload());
+        assert_eq!(current_pem(&cert), second);
+        fs::remove_file(&cert_path).ok();
+        fs::remove_file(&key_path).ok();
+    }
+}
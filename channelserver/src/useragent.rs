@@ -0,0 +1,143 @@
+//! Structured User-Agent parsing.
+//!
+//! Peers are told the browser, its major version, the OS and the class of
+//! device the other side is using (e.g. Firefox 79 on Android, a phone), so
+//! they can describe it without parsing UA strings themselves.
+use woothee::parser::Parser;
+
+const VALUE_UNKNOWN: &str = "UNKNOWN";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceClass {
+    Desktop,
+    Phone,
+    Tablet,
+    Other,
+}
+
+impl DeviceClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Phone => "phone",
+            DeviceClass::Tablet => "tablet",
+            DeviceClass::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UserAgent {
+    pub browser: Option<String>,
+    /// Major version only
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub device: Option<DeviceClass>,
+}
+
+fn known(value: &str) -> Option<String> {
+    if value.is_empty() || value == VALUE_UNKNOWN {
+        return None;
+    }
+    Some(value.to_owned())
+}
+
+/// Collapse OS names to their family, e.g. "Windows 10" to "Windows".
+fn os_family(os: &str) -> &str {
+    match os {
+        _ if os.starts_with("Windows") => "Windows",
+        "Mac OSX" => "macOS",
+        "iPhone" | "iPad" | "iPod" => "iOS",
+        _ => os,
+    }
+}
+
+pub fn parse(ua: &str) -> UserAgent {
+    let parsed = match Parser::new().parse(ua) {
+        Some(parsed) => parsed,
+        None => return UserAgent::default(),
+    };
+    let mut result = UserAgent {
+        browser: known(parsed.name),
+        browser_version: known(parsed.version.split('.').next().unwrap_or_default()),
+        os: known(os_family(parsed.os)),
+        device: match parsed.category {
+            "pc" => Some(DeviceClass::Desktop),
+            "smartphone" | "mobilephone" => Some(DeviceClass::Phone),
+            VALUE_UNKNOWN => None,
+            _ => Some(DeviceClass::Other),
+        },
+    };
+    // Firefox and Android tablets say so; iPads are only told apart by name.
+    if result.device == Some(DeviceClass::Phone) && (ua.contains("Tablet") || ua.contains("iPad")) {
+        result.device = Some(DeviceClass::Tablet);
+    }
+    // Firefox for iOS is a Safari webview, but calls itself Firefox.
+    if let Some(pos) = ua.find("FxiOS/") {
+        result.browser = Some("Firefox".to_owned());
+        result.browser_version = ua[pos + 6..]
+            .split(|c: char| c == '.' || c.is_whitespace())
+            .next()
+            .and_then(known);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(ua: &str, browser: &str, version: &str, os: &str, device: DeviceClass) {
+        let parsed = parse(ua);
+        assert_eq!(parsed.browser.as_deref(), Some(browser), "{}", ua);
+        assert_eq!(parsed.browser_version.as_deref(), Some(version), "{}", ua);
+        assert_eq!(parsed.os.as_deref(), Some(os), "{}", ua);
+        assert_eq!(parsed.device, Some(device), "{}", ua);
+    }
+
+    #[test]
+    fn test_parse() {
+        check(
+            "Mozilla/5.0 (Android 10; Mobile; rv:79.0) Gecko/79.0 Firefox/79.0",
+            "Firefox",
+            "79",
+            "Android",
+            DeviceClass::Phone,
+        );
+        check(
+            "Mozilla/5.0 (Android 9; Tablet; rv:68.0) Gecko/68.0 Firefox/68.0",
+            "Firefox",
+            "68",
+            "Android",
+            DeviceClass::Tablet,
+        );
+        check(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:80.0) Gecko/20100101 Firefox/80.0",
+            "Firefox",
+            "80",
+            "Windows",
+            DeviceClass::Desktop,
+        );
+        check(
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:80.0) Gecko/20100101 Firefox/80.0",
+            "Firefox",
+            "80",
+            "macOS",
+            DeviceClass::Desktop,
+        );
+        check(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 13_6 like Mac OS X) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) FxiOS/28.1 Mobile/15E148 Safari/605.1.15",
+            "Firefox",
+            "28",
+            "iOS",
+            DeviceClass::Phone,
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown() {
+        assert_eq!(parse(""), UserAgent::default());
+        assert_eq!(parse("Mozilla/5.0 Foo").browser, None);
+    }
+}